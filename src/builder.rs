@@ -0,0 +1,666 @@
+use crate::{
+    find_state, Action, ConflictPolicy, DefinitionError, DeserializableEdge, DispatchHook, Edge,
+    EdgeTraversalHook, Event, EventQueue, Guard, Handler, HistoryKind, HistoryStore,
+    HydrationError, MachineDefinition,
+    SharedHandler, SharedHook, State, StateHook, StateMachine, DEFAULT_EVENTLESS_STEP_LIMIT,
+};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+/// Hooks for a single state or edge, by its string id.
+type NamedHooks<T> = Vec<(String, SharedHook<T>)>;
+
+/// Guards by edge id, with the function copying the context for edges without an action.
+type NamedGuards<'a, EventPayload, Context> =
+    Vec<(String, Arc<Guard<'a, EventPayload, Context>>, fn(&Context) -> Context)>;
+
+/// Assembles a `StateMachine` piece by piece and validates it on `build`.
+pub struct StateMachineBuilder<'a, EventPayload, EdgeInfo, Context> {
+    initial_state_id: Option<String>,
+    initial_context: Option<Context>,
+    states: Vec<State>,
+    edges: Vec<DeserializableEdge<EdgeInfo>>,
+    state_parents: Vec<(String, String)>,
+    initial_children: Vec<(String, String)>,
+    parallel_states: Vec<String>,
+    history_states: Vec<(String, HistoryKind)>,
+    final_states: Vec<String>,
+    edge_priorities: Vec<(String, i32)>,
+    edge_triggers: Vec<(String, String)>,
+    eventless_edges: Vec<String>,
+    conflict_policy: ConflictPolicy<'a, EdgeInfo>,
+    eventless_step_limit: usize,
+    history_store: Option<Box<dyn HistoryStore<EventPayload, Context> + 'a>>,
+    event_queue: Option<EventQueue<EventPayload>>,
+    event_handler: Option<SharedHandler<'a, EventPayload, EdgeInfo, Context>>,
+    guards: NamedGuards<'a, EventPayload, Context>,
+    actions: Vec<(String, Arc<Action<'a, EventPayload, Context>>)>,
+    start_dispatch_hook: Option<SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>>,
+    end_dispatch_hook: Option<SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>>,
+    completion_hook: Option<SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>>,
+    on_state_entry_hook: Option<SharedHook<StateHook<'a, EventPayload, EdgeInfo, Context>>>,
+    on_state_exit_hook: Option<SharedHook<StateHook<'a, EventPayload, EdgeInfo, Context>>>,
+    on_edge_traversal_hook:
+        Option<SharedHook<EdgeTraversalHook<'a, EventPayload, EdgeInfo, Context>>>,
+    state_entry_hooks: NamedHooks<StateHook<'a, EventPayload, EdgeInfo, Context>>,
+    state_exit_hooks: NamedHooks<StateHook<'a, EventPayload, EdgeInfo, Context>>,
+    edge_traversal_hooks: NamedHooks<EdgeTraversalHook<'a, EventPayload, EdgeInfo, Context>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    MissingInitialState,
+    MissingContext,
+    MissingHandler,
+    Definition(DefinitionError),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::MissingInitialState => {
+                write!(f, "No initial state was set, call `initial` before `build`")
+            }
+            BuildError::MissingContext => {
+                write!(f, "No initial context was set, call `context` before `build`")
+            }
+            BuildError::MissingHandler => {
+                write!(
+                    f,
+                    "No event handler was set and not every edge has a guard or an action, \
+                     call `handler` before `build`"
+                )
+            }
+            BuildError::Definition(error) => Display::fmt(error, f),
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::Definition(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<DefinitionError> for BuildError {
+    fn from(error: DefinitionError) -> Self {
+        BuildError::Definition(error)
+    }
+}
+
+impl From<HydrationError> for BuildError {
+    fn from(error: HydrationError) -> Self {
+        BuildError::Definition(error.into())
+    }
+}
+
+impl<'a, EventPayload, EdgeInfo, Context> Default
+    for StateMachineBuilder<'a, EventPayload, EdgeInfo, Context>
+{
+    fn default() -> Self {
+        StateMachineBuilder {
+            initial_state_id: None,
+            initial_context: None,
+            states: Vec::new(),
+            edges: Vec::new(),
+            state_parents: Vec::new(),
+            initial_children: Vec::new(),
+            parallel_states: Vec::new(),
+            history_states: Vec::new(),
+            final_states: Vec::new(),
+            edge_priorities: Vec::new(),
+            edge_triggers: Vec::new(),
+            eventless_edges: Vec::new(),
+            conflict_policy: ConflictPolicy::Reject,
+            eventless_step_limit: DEFAULT_EVENTLESS_STEP_LIMIT,
+            history_store: None,
+            event_queue: None,
+            event_handler: None,
+            guards: Vec::new(),
+            actions: Vec::new(),
+            start_dispatch_hook: None,
+            end_dispatch_hook: None,
+            completion_hook: None,
+            on_state_entry_hook: None,
+            on_state_exit_hook: None,
+            on_edge_traversal_hook: None,
+            state_entry_hooks: Vec::new(),
+            state_exit_hooks: Vec::new(),
+            edge_traversal_hooks: Vec::new(),
+        }
+    }
+}
+
+impl<'a, EventPayload, EdgeInfo, Context> StateMachineBuilder<'a, EventPayload, EdgeInfo, Context>
+where
+    EventPayload: Debug + Send + Sync + 'a,
+    EdgeInfo: Debug,
+    Context: Debug + Send + 'a,
+{
+    pub fn new() -> Self {
+        StateMachineBuilder::default()
+    }
+
+    /// Starts from the states, edges and initial state of `definition`.
+    pub fn from_definition(definition: MachineDefinition<EdgeInfo>) -> Self {
+        StateMachineBuilder {
+            initial_state_id: Some(definition.initial_state_id),
+            states: definition.states,
+            edges: definition.edges,
+            ..StateMachineBuilder::default()
+        }
+    }
+
+    pub fn state(mut self, id: impl Into<String>) -> Self {
+        self.states.push(State::new(id));
+        self
+    }
+
+    /// Nests the state with id `state_id` in the state with id `parent_id`, see
+    /// `State::with_parent`.
+    pub fn parent(mut self, state_id: impl Into<String>, parent_id: impl Into<String>) -> Self {
+        self.state_parents.push((state_id.into(), parent_id.into()));
+        self
+    }
+
+    /// Sets the child entered when the state with id `state_id` is entered, see
+    /// `State::with_initial`.
+    pub fn initial_child(
+        mut self,
+        state_id: impl Into<String>,
+        child_id: impl Into<String>,
+    ) -> Self {
+        self.initial_children.push((state_id.into(), child_id.into()));
+        self
+    }
+
+    /// Makes every child of the state with id `state_id` a parallel region, see
+    /// `State::with_parallel_regions`.
+    pub fn parallel(mut self, state_id: impl Into<String>) -> Self {
+        self.parallel_states.push(state_id.into());
+        self
+    }
+
+    /// Makes the state with id `state_id` a history pseudo-state of its parent, see
+    /// `State::with_history`.
+    pub fn history(mut self, state_id: impl Into<String>, kind: HistoryKind) -> Self {
+        self.history_states.push((state_id.into(), kind));
+        self
+    }
+
+    /// Marks the state with id `state_id` as final, see `State::with_final`.
+    pub fn final_state(mut self, state_id: impl Into<String>) -> Self {
+        self.final_states.push(state_id.into());
+        self
+    }
+
+    pub fn edge(
+        mut self,
+        id: impl Into<String>,
+        from_state_id: impl Into<String>,
+        to_state_id: impl Into<String>,
+        info: EdgeInfo,
+    ) -> Self {
+        self.edges.push(DeserializableEdge::new(id, from_state_id, to_state_id, info));
+        self
+    }
+
+    /// Sets the priority used by `ConflictPolicy::Priority` on the edge with id `edge_id`.
+    pub fn priority(mut self, edge_id: impl Into<String>, priority: i32) -> Self {
+        self.edge_priorities.push((edge_id.into(), priority));
+        self
+    }
+
+    /// Adds `event_id` to the triggers of the edge with id `edge_id`, see
+    /// `Edge::with_triggers`.
+    pub fn trigger(mut self, edge_id: impl Into<String>, event_id: impl Into<String>) -> Self {
+        self.edge_triggers.push((edge_id.into(), event_id.into()));
+        self
+    }
+
+    /// Makes the edge with id `edge_id` eventless, see `Edge::with_eventless`.
+    pub fn eventless(mut self, edge_id: impl Into<String>) -> Self {
+        self.eventless_edges.push(edge_id.into());
+        self
+    }
+
+    pub fn conflict_policy(mut self, conflict_policy: ConflictPolicy<'a, EdgeInfo>) -> Self {
+        self.conflict_policy = conflict_policy;
+        self
+    }
+
+    /// See `StateMachine::set_eventless_step_limit`.
+    pub fn eventless_step_limit(mut self, eventless_step_limit: usize) -> Self {
+        self.eventless_step_limit = eventless_step_limit;
+        self
+    }
+
+    /// See `StateMachine::set_history_store`.
+    pub fn history_store(
+        mut self,
+        history_store: impl HistoryStore<EventPayload, Context> + 'a,
+    ) -> Self {
+        self.history_store = Some(Box::new(history_store));
+        self
+    }
+
+    /// See `StateMachine::set_event_queue`.
+    pub fn event_queue(mut self, event_queue: EventQueue<EventPayload>) -> Self {
+        self.event_queue = Some(event_queue);
+        self
+    }
+
+    pub fn initial(mut self, state_id: impl Into<String>) -> Self {
+        self.initial_state_id = Some(state_id.into());
+        self
+    }
+
+    pub fn context(mut self, initial_context: Context) -> Self {
+        self.initial_context = Some(initial_context);
+        self
+    }
+
+    pub fn handler(
+        mut self,
+        event_handler: impl Handler<EventPayload, EdgeInfo, Context> + 'a,
+    ) -> Self {
+        self.event_handler = Some(Arc::new(event_handler));
+        self
+    }
+
+    /// See `StateMachine::set_guard`.
+    pub fn guard(
+        mut self,
+        edge_id: impl Into<String>,
+        guard: impl Fn(&Event<EventPayload>, &Context) -> bool + Send + Sync + 'a,
+    ) -> Self
+    where
+        Context: Clone,
+    {
+        self.guards
+            .push((edge_id.into(), Arc::new(guard), Context::clone));
+        self
+    }
+
+    /// See `StateMachine::set_action`.
+    pub fn action(
+        mut self,
+        edge_id: impl Into<String>,
+        action: impl Fn(&Event<EventPayload>, &Context) -> Context + Send + Sync + 'a,
+    ) -> Self {
+        self.actions.push((edge_id.into(), Arc::new(action)));
+        self
+    }
+
+    pub fn on_start_dispatch(
+        mut self,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Self {
+        self.start_dispatch_hook = Some(Arc::new(Mutex::new(hook)));
+        self
+    }
+
+    pub fn on_end_dispatch(
+        mut self,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Self {
+        self.end_dispatch_hook = Some(Arc::new(Mutex::new(hook)));
+        self
+    }
+
+    /// See `StateMachine::set_completion_hook`.
+    pub fn on_completion(
+        mut self,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Self {
+        self.completion_hook = Some(Arc::new(Mutex::new(hook)));
+        self
+    }
+
+    pub fn on_state_entry(
+        mut self,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Self {
+        self.on_state_entry_hook = Some(Arc::new(Mutex::new(hook)));
+        self
+    }
+
+    pub fn on_state_exit(
+        mut self,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Self {
+        self.on_state_exit_hook = Some(Arc::new(Mutex::new(hook)));
+        self
+    }
+
+    pub fn on_edge_traversal(
+        mut self,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Self {
+        self.on_edge_traversal_hook = Some(Arc::new(Mutex::new(hook)));
+        self
+    }
+
+    /// See `StateMachine::add_state_entry_hook`.
+    pub fn on_enter(
+        mut self,
+        state_id: impl Into<String>,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Self {
+        self.state_entry_hooks
+            .push((state_id.into(), Arc::new(Mutex::new(hook))));
+        self
+    }
+
+    /// See `StateMachine::add_state_exit_hook`.
+    pub fn on_exit(
+        mut self,
+        state_id: impl Into<String>,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Self {
+        self.state_exit_hooks
+            .push((state_id.into(), Arc::new(Mutex::new(hook))));
+        self
+    }
+
+    /// See `StateMachine::add_edge_traversal_hook`.
+    pub fn on_traverse(
+        mut self,
+        edge_id: impl Into<String>,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Self {
+        self.edge_traversal_hooks
+            .push((edge_id.into(), Arc::new(Mutex::new(hook))));
+        self
+    }
+
+    pub fn build(
+        self,
+    ) -> Result<StateMachine<'a, EventPayload, EdgeInfo, Context>, BuildError> {
+        let initial_state_id = self
+            .initial_state_id
+            .ok_or(BuildError::MissingInitialState)?;
+        let initial_context = self.initial_context.ok_or(BuildError::MissingContext)?;
+        // Only edges without a guard or an action need the handler.
+        let (guards, actions) = (&self.guards, &self.actions);
+        let covered = |edge: &DeserializableEdge<EdgeInfo>| {
+            guards.iter().any(|(edge_id, _, _)| *edge_id == edge.id)
+                || actions.iter().any(|(edge_id, _)| *edge_id == edge.id)
+        };
+        if self.event_handler.is_none() && !self.edges.iter().all(covered) {
+            return Err(BuildError::MissingHandler);
+        }
+        let mut states = self.states;
+        for (state_id, parent_id) in self.state_parents {
+            let state = find_state(&states, &state_id, "state_id")?;
+            states[state.0].parent = Some(parent_id);
+        }
+        for (state_id, child_id) in self.initial_children {
+            let state = find_state(&states, &state_id, "state_id")?;
+            states[state.0].initial = Some(child_id);
+        }
+        for state_id in self.parallel_states {
+            let state = find_state(&states, &state_id, "parallel")?;
+            states[state.0].parallel = true;
+        }
+        for (state_id, kind) in self.history_states {
+            let state = find_state(&states, &state_id, "history")?;
+            states[state.0].history = Some(kind);
+        }
+        for state_id in self.final_states {
+            let state = find_state(&states, &state_id, "final")?;
+            states[state.0].final_state = true;
+        }
+        let mut edges = self.edges;
+        for (edge_id, priority) in self.edge_priorities {
+            edges
+                .iter_mut()
+                .find(|edge| edge.id == edge_id)
+                .ok_or(HydrationError::MissingEdge {
+                    id: edge_id,
+                    field: "priority",
+                })?
+                .priority = priority;
+        }
+        for (edge_id, event_id) in self.edge_triggers {
+            edges
+                .iter_mut()
+                .find(|edge| edge.id == edge_id)
+                .ok_or(HydrationError::MissingEdge {
+                    id: edge_id,
+                    field: "trigger",
+                })?
+                .triggers
+                .push(event_id);
+        }
+        for edge_id in self.eventless_edges {
+            edges
+                .iter_mut()
+                .find(|edge| edge.id == edge_id)
+                .ok_or(HydrationError::MissingEdge {
+                    id: edge_id,
+                    field: "eventless",
+                })?
+                .eventless = true;
+        }
+        let (initial_state, states, edges, hierarchy) = MachineDefinition {
+            initial_state_id,
+            states,
+            edges,
+        }
+        .hydrate()?;
+
+        let mut state_machine = StateMachine::from_parts(
+            initial_state,
+            initial_context,
+            states,
+            edges,
+            hierarchy,
+            self.event_handler,
+            self.start_dispatch_hook,
+            self.end_dispatch_hook,
+            self.on_state_entry_hook,
+            self.on_state_exit_hook,
+            self.on_edge_traversal_hook,
+        );
+        state_machine.conflict_policy = self.conflict_policy;
+        state_machine.eventless_step_limit = self.eventless_step_limit;
+        if let Some(history_store) = self.history_store {
+            state_machine.transition_history = history_store;
+        }
+        if let Some(event_queue) = self.event_queue {
+            state_machine.event_queue = event_queue;
+        }
+        state_machine.completion_hook = self.completion_hook;
+        for (state_id, hook) in self.state_entry_hooks {
+            let state = find_state(&state_machine.states, &state_id, "state_id")?;
+            state_machine
+                .state_entry_hooks
+                .entry(state)
+                .or_default()
+                .push(hook);
+        }
+        for (state_id, hook) in self.state_exit_hooks {
+            let state = find_state(&state_machine.states, &state_id, "state_id")?;
+            state_machine
+                .state_exit_hooks
+                .entry(state)
+                .or_default()
+                .push(hook);
+        }
+        for (edge_id, guard, keep_context) in self.guards {
+            let edge = state_machine.find_edge(&edge_id)?;
+            state_machine.insert_guard(edge, guard, keep_context);
+        }
+        for (edge_id, action) in self.actions {
+            let edge = state_machine.find_edge(&edge_id)?;
+            state_machine.insert_action(edge, action);
+        }
+        for (edge_id, hook) in self.edge_traversal_hooks {
+            let edge = state_machine
+                .edge_id(&edge_id)
+                .ok_or(HydrationError::MissingEdge {
+                    id: edge_id,
+                    field: "edge_id",
+                })?;
+            state_machine
+                .edge_traversal_hooks
+                .entry(edge)
+                .or_default()
+                .push(hook);
+        }
+        Ok(state_machine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+        Some(())
+    }
+
+    #[test]
+    fn it_builds_a_state_machine() {
+        let mut entered = Vec::new();
+        let mut state_machine = StateMachineBuilder::new()
+            .state("first_state")
+            .state("second_state")
+            .edge("from first to second", "first_state", "second_state", "do it".to_string())
+            .initial("first_state")
+            .context(())
+            .handler(event_handler)
+            .on_enter(
+                "second_state",
+                |_: &Event<()>,
+                 state: &State,
+                 _: &Edge<String>,
+                 _: &(),
+                 _: &Vec<State>,
+                 _: &Vec<Edge<String>>| entered.push(state.id().to_string()),
+            )
+            .build()
+            .unwrap();
+
+        let event1 = Event::new("first event", ());
+        state_machine.dispatch(event1.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("second_state"));
+        std::mem::drop(state_machine);
+        assert_eq!(entered, vec!["second_state"]);
+    }
+
+    #[test]
+    fn it_reports_missing_pieces() {
+        let builder = || {
+            StateMachineBuilder::<(), String, ()>::new()
+                .state("first_state")
+                .edge("from first to second", "first_state", "second_state", "do it".to_string())
+        };
+
+        assert_eq!(
+            builder().context(()).handler(event_handler).build().err(),
+            Some(BuildError::MissingInitialState)
+        );
+        assert_eq!(
+            builder().initial("first_state").handler(event_handler).build().err(),
+            Some(BuildError::MissingContext)
+        );
+        assert_eq!(
+            builder().initial("first_state").context(()).build().err(),
+            Some(BuildError::MissingHandler)
+        );
+        assert_eq!(
+            builder()
+                .initial("first_state")
+                .context(())
+                .handler(event_handler)
+                .build()
+                .err(),
+            Some(BuildError::Definition(DefinitionError::Hydration(
+                HydrationError::MissingState {
+                    id: "second_state".to_string(),
+                    field: "to_state_id",
+                }
+            )))
+        );
+        assert_eq!(
+            builder()
+                .state("second_state")
+                .parent("third_state", "first_state")
+                .initial("first_state")
+                .context(())
+                .handler(event_handler)
+                .build()
+                .err(),
+            Some(BuildError::Definition(DefinitionError::Hydration(
+                HydrationError::MissingState {
+                    id: "third_state".to_string(),
+                    field: "state_id",
+                }
+            )))
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{EdgeId, HistoryKind, StateId, StateMachine};
+use std::collections::HashMap;
+use std::fmt::{Debug, Write};
+
+impl<'a, EventPayload, EdgeInfo, Context> StateMachine<'a, EventPayload, EdgeInfo, Context>
+where
+    EventPayload: Debug + Send + Sync + 'a,
+    EdgeInfo: Debug,
+    Context: Debug + Send + 'a,
+{
+    /// Renders the machine as a Graphviz DOT digraph: every state is a node, and every edge
+    /// an arrow labelled with its id and whatever `format_info` makes of its info. Final
+    /// states are drawn with a double circle, history pseudo-states as `H` or `H*`, and the
+    /// current state and every active state are filled in.
+    pub fn to_dot(&self, format_info: impl Fn(&EdgeInfo) -> String) -> String {
+        self.write_dot(format_info, None)
+    }
+
+    /// Like `to_dot`, but colours every edge by how often it appears in
+    /// `transition_history`, shading from blue towards red, and thicker, the closer it comes
+    /// to the most taken edge. Edges that were never taken stay black.
+    pub fn to_dot_with_history(&self, format_info: impl Fn(&EdgeInfo) -> String) -> String {
+        let mut counts = HashMap::new();
+        for record in self.transition_history.iter() {
+            *counts.entry(record.edge).or_insert(0) += 1;
+        }
+        self.write_dot(format_info, Some(counts))
+    }
+
+    fn write_dot(
+        &self,
+        format_info: impl Fn(&EdgeInfo) -> String,
+        counts: Option<HashMap<EdgeId, usize>>,
+    ) -> String {
+        let current_state = self.current_state();
+        let mut dot = String::from("digraph {\n");
+        for (index, state) in self.states.iter().enumerate() {
+            let mut attributes = Vec::new();
+            match state.history {
+                Some(HistoryKind::Shallow) => attributes.push("label=\"H\"".to_string()),
+                Some(HistoryKind::Deep) => attributes.push("label=\"H*\"".to_string()),
+                None => {}
+            }
+            if state.history.is_some() {
+                attributes.push("shape=circle".to_string());
+            } else if state.final_state {
+                attributes.push("shape=doublecircle".to_string());
+            }
+            let state_id = StateId(index);
+            if current_state == Some(state_id) || self.active_states.contains(&state_id) {
+                attributes.push("style=filled".to_string());
+                attributes.push("fillcolor=lightblue".to_string());
+            }
+            write_statement(&mut dot, &quote(&state.id), &attributes);
+        }
+        let max_count = counts
+            .as_ref()
+            .and_then(|counts| counts.values().copied().max())
+            .unwrap_or(0);
+        for (index, edge) in self.edges.iter().enumerate() {
+            let states = (self.states.get(edge.from_state.0), self.states.get(edge.to_state.0));
+            let (from, to) = match states {
+                (Some(from), Some(to)) => (from, to),
+                _ => continue,
+            };
+            let info = format_info(&edge.info);
+            let label = if info.is_empty() {
+                edge.id.clone()
+            } else {
+                format!("{}\n{}", edge.id, info)
+            };
+            let mut attributes = vec![format!("label={}", quote(&label))];
+            let count = counts
+                .as_ref()
+                .and_then(|counts| counts.get(&EdgeId(index)).copied())
+                .unwrap_or(0);
+            if count > 0 {
+                // Hue 2/3 is blue and 0 is red.
+                let heat = count as f64 / max_count as f64;
+                attributes.push(format!("color=\"{:.3} 1.000 1.000\"", (1.0 - heat) * 2.0 / 3.0));
+                attributes.push(format!("penwidth={:.1}", 1.0 + 2.0 * heat));
+            }
+            write_statement(
+                &mut dot,
+                &format!("{} -> {}", quote(&from.id), quote(&to.id)),
+                &attributes,
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn write_statement(dot: &mut String, statement: &str, attributes: &[String]) {
+    if attributes.is_empty() {
+        writeln!(dot, "    {};", statement).unwrap();
+    } else {
+        writeln!(dot, "    {} [{}];", statement, attributes.join(", ")).unwrap();
+    }
+}
+
+/// `id` as a DOT string, with quotes and backslashes escaped and newlines as `\n`.
+fn quote(id: &str) -> String {
+    let escaped = id
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn it_renders_the_machine_as_dot() {
+        let mut state_machine: StateMachine<(), String, ()> = StateMachineBuilder::new()
+            .state("created")
+            .state("paid")
+            .state("shipped")
+            .final_state("shipped")
+            .edge("pay", "created", "paid", "card".to_string())
+            .edge("refund", "paid", "created", String::new())
+            .edge("ship", "paid", "shipped", "\"express\"".to_string())
+            .initial("created")
+            .context(())
+            .handler(|event: &Event<()>, edge: &Edge<String>, _: &()| {
+                if edge.id() == event.id() {
+                    Some(())
+                } else {
+                    None
+                }
+            })
+            .build()
+            .unwrap();
+        for id in ["pay", "refund", "pay"].iter() {
+            state_machine.dispatch(Event::new(*id, ()));
+        }
+
+        assert_eq!(
+            state_machine.to_dot(|info| info.clone()),
+            r#"digraph {
+    "created";
+    "paid" [style=filled, fillcolor=lightblue];
+    "shipped" [shape=doublecircle];
+    "created" -> "paid" [label="pay\ncard"];
+    "paid" -> "created" [label="refund"];
+    "paid" -> "shipped" [label="ship\n\"express\""];
+}
+"#
+        );
+        assert_eq!(
+            state_machine.to_dot_with_history(|_| String::new()),
+            r#"digraph {
+    "created";
+    "paid" [style=filled, fillcolor=lightblue];
+    "shipped" [shape=doublecircle];
+    "created" -> "paid" [label="pay", color="0.000 1.000 1.000", penwidth=3.0];
+    "paid" -> "created" [label="refund", color="0.333 1.000 1.000", penwidth=2.0];
+    "paid" -> "shipped" [label="ship"];
+}
+"#
+        );
+    }
+}
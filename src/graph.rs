@@ -0,0 +1,250 @@
+use crate::{EdgeId, StateId, StateMachine};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::Debug;
+
+impl<'a, EventPayload, EdgeInfo, Context> StateMachine<'a, EventPayload, EdgeInfo, Context>
+where
+    EventPayload: Debug + Send + Sync + 'a,
+    EdgeInfo: Debug,
+    Context: Debug + Send + 'a,
+{
+    /// Every state that is active in some configuration reachable from `from`, including the
+    /// states active once `from` is entered. Guards and the handler are not consulted, so an
+    /// edge counts as taken as soon as its source is active.
+    pub fn reachable_states(&self, from: StateId) -> BTreeSet<StateId> {
+        let mut reachable = BTreeSet::new();
+        let mut pending = self.entered_states(from);
+        while let Some(state) = pending.pop() {
+            if reachable.insert(state) {
+                pending.extend(self.successors(state).into_iter().flat_map(|(_, entered)| entered));
+            }
+        }
+        reachable
+    }
+
+    /// The fewest edges to take from `from` until `to` is active, regardless of guards, or
+    /// `None` if `to` is not reachable. The path is empty if entering `from` makes `to`
+    /// active.
+    pub fn shortest_path(&self, from: StateId, to: StateId) -> Option<Vec<EdgeId>> {
+        // The edge each state was first reached through, and the state it was taken from.
+        let mut reached_by = HashMap::new();
+        let mut pending = VecDeque::new();
+        for state in self.entered_states(from) {
+            reached_by.insert(state, None);
+            pending.push_back(state);
+        }
+        while let Some(state) = pending.pop_front() {
+            if state == to {
+                let mut path = Vec::new();
+                let mut state = state;
+                while let Some((edge, previous)) = reached_by[&state] {
+                    path.push(edge);
+                    state = previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for (edge, entered) in self.successors(state) {
+                for entered in entered {
+                    reached_by.entry(entered).or_insert_with(|| {
+                        pending.push_back(entered);
+                        Some((edge, state))
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// The strongly connected components of the graph where every state other than a
+    /// history pseudo-state leads to the states its edges, and those of its ancestors,
+    /// enter. Within a component every state can be reached from every other one, so a
+    /// component of more than one state, or of a single state that leads to itself, is a
+    /// cycle.
+    ///
+    /// Components are listed after every component they lead to, states in a component in
+    /// index order.
+    pub fn strongly_connected_components(&self) -> Vec<Vec<StateId>> {
+        // Tarjan's algorithm, with an explicit stack of the successors left to visit.
+        let mut index = HashMap::new();
+        let mut low_link = HashMap::new();
+        let mut stack = Vec::new();
+        let mut on_stack = BTreeSet::new();
+        let mut components = Vec::new();
+        for root in self.graph_states() {
+            if index.contains_key(&root) {
+                continue;
+            }
+            let mut visiting = vec![(root, self.successor_states(root))];
+            index.insert(root, index.len());
+            low_link.insert(root, index[&root]);
+            stack.push(root);
+            on_stack.insert(root);
+            while let Some((state, successors)) = visiting.last_mut() {
+                let state = *state;
+                if let Some(successor) = successors.pop() {
+                    if !index.contains_key(&successor) {
+                        index.insert(successor, index.len());
+                        low_link.insert(successor, index[&successor]);
+                        stack.push(successor);
+                        on_stack.insert(successor);
+                        visiting.push((successor, self.successor_states(successor)));
+                    } else if on_stack.contains(&successor) {
+                        let low = low_link[&state].min(index[&successor]);
+                        low_link.insert(state, low);
+                    }
+                    continue;
+                }
+                visiting.pop();
+                if let Some((parent, _)) = visiting.last() {
+                    let low = low_link[parent].min(low_link[&state]);
+                    low_link.insert(*parent, low);
+                }
+                if low_link[&state] == index[&state] {
+                    let mut component = Vec::new();
+                    while let Some(member) = stack.pop() {
+                        on_stack.remove(&member);
+                        component.push(member);
+                        if member == state {
+                            break;
+                        }
+                    }
+                    component.sort();
+                    components.push(component);
+                }
+            }
+        }
+        components
+    }
+
+    /// The leaf states that neither they nor any of their ancestors have an edge out of,
+    /// so once active they stay active. History pseudo-states are never active, so they are
+    /// not sinks.
+    pub fn sinks(&self) -> Vec<StateId> {
+        self.graph_states()
+            .filter(|state| self.hierarchy.children(*state).is_empty())
+            .filter(|state| {
+                self.hierarchy
+                    .ancestors(*state)
+                    .all(|state| self.state_to_edge_map[&state].is_empty())
+            })
+            .collect()
+    }
+
+    /// Every state other than the history pseudo-states.
+    fn graph_states(&self) -> impl Iterator<Item = StateId> + '_ {
+        let hierarchy = &self.hierarchy;
+        (0..self.states.len())
+            .map(StateId)
+            .filter(move |state| hierarchy.history(*state).is_none())
+    }
+
+    /// The states that are active once `state` is entered from outside its outermost
+    /// ancestor. An edge into a history pseudo-state enters its parent.
+    fn entered_states(&self, state: StateId) -> Vec<StateId> {
+        if state.0 >= self.states.len() {
+            return Vec::new();
+        }
+        let state = match self.hierarchy.history(state) {
+            Some(_) => match self.hierarchy.parent(state) {
+                Some(parent) => parent,
+                None => return Vec::new(),
+            },
+            None => state,
+        };
+        self.hierarchy.entry(None, state, &[]).0
+    }
+
+    /// The edges that can be taken while `state` is active, each with the states it enters.
+    fn successors(&self, state: StateId) -> Vec<(EdgeId, Vec<StateId>)> {
+        self.hierarchy
+            .ancestors(state)
+            .flat_map(|state| self.state_to_edge_map[&state].iter().copied())
+            .map(|edge| (edge, self.entered_states(self.edges[edge.0].to_state)))
+            .collect()
+    }
+
+    fn successor_states(&self, state: StateId) -> Vec<StateId> {
+        let successors = self
+            .successors(state)
+            .into_iter()
+            .flat_map(|(_, entered)| entered)
+            .collect::<BTreeSet<_>>();
+        successors.into_iter().rev().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::media_player;
+    use crate::*;
+
+    fn build() -> StateMachine<'static, (), (), ()> {
+        StateMachineBuilder::new()
+            .state("created")
+            .state("paid")
+            .state("shipped")
+            .state("refunded")
+            .state("archived")
+            .edge("pay", "created", "paid", ())
+            .edge("cancel", "paid", "created", ())
+            .edge("ship", "paid", "shipped", ())
+            .edge("refund", "shipped", "refunded", ())
+            .edge("restore", "archived", "created", ())
+            .initial("created")
+            .context(())
+            .handler(|_: &Event<()>, _: &Edge<()>, _: &()| None)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn it_analyses_the_graph_of_states_and_edges() {
+        let state_machine = build();
+        let state = |id| state_machine.state_id(id).unwrap();
+        let edge = |id| state_machine.edge_id(id).unwrap();
+
+        assert_eq!(
+            state_machine.reachable_states(state("created")),
+            vec![state("created"), state("paid"), state("shipped"), state("refunded")]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            state_machine.shortest_path(state("created"), state("refunded")),
+            Some(vec![edge("pay"), edge("ship"), edge("refund")])
+        );
+        assert_eq!(state_machine.shortest_path(state("paid"), state("paid")), Some(Vec::new()));
+        assert_eq!(state_machine.shortest_path(state("refunded"), state("created")), None);
+        assert_eq!(
+            state_machine.strongly_connected_components(),
+            vec![
+                vec![state("refunded")],
+                vec![state("shipped")],
+                vec![state("created"), state("paid")],
+                vec![state("archived")],
+            ]
+        );
+        assert_eq!(state_machine.sinks(), vec![state("refunded")]);
+    }
+
+    #[test]
+    fn it_follows_edges_of_ancestors_and_into_history() {
+        let state_machine = media_player();
+        let state = |id| state_machine.state_id(id).unwrap();
+        let edge = |id| state_machine.edge_id(id).unwrap();
+
+        assert_eq!(
+            state_machine.shortest_path(state("off"), state("paused")),
+            Some(vec![edge("start"), edge("pause")])
+        );
+        assert_eq!(
+            state_machine.shortest_path(state("paused"), state("off")),
+            Some(vec![edge("stop")])
+        );
+        assert!(state_machine
+            .strongly_connected_components()
+            .contains(&vec![state("player"), state("playing"), state("paused"), state("off")]));
+        assert!(state_machine.sinks().is_empty());
+    }
+}
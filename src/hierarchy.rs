@@ -0,0 +1,213 @@
+use crate::{find_state, DefinitionError, HistoryKind, State, StateId};
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+
+/// The parent and initial child of every state, resolved into `StateId`s.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Hierarchy {
+    parents: Vec<Option<StateId>>,
+    initial_children: Vec<Option<StateId>>,
+    /// The children of every state other than history pseudo-states, in the order the states
+    /// were defined.
+    children: Vec<Vec<StateId>>,
+    parallel: Vec<bool>,
+    history: Vec<Option<HistoryKind>>,
+}
+
+impl Hierarchy {
+    pub(crate) fn resolve(states: &[State]) -> Result<Hierarchy, DefinitionError> {
+        let resolve = |id: Option<&str>, field| {
+            id.map(|id| find_state(states, id, field)).transpose()
+        };
+        let parents = states
+            .iter()
+            .map(|state| resolve(state.parent(), "parent"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut children = vec![Vec::new(); states.len()];
+        for (index, parent) in parents.iter().enumerate() {
+            match (parent, states[index].history()) {
+                (Some(parent), None) => children[parent.0].push(StateId(index)),
+                (None, Some(_)) => {
+                    return Err(DefinitionError::HistoryWithoutParent {
+                        id: states[index].id().to_string(),
+                    })
+                }
+                _ => {}
+            }
+        }
+        let hierarchy = Hierarchy {
+            parents,
+            initial_children: states
+                .iter()
+                .map(|state| resolve(state.initial(), "initial"))
+                .collect::<Result<_, _>>()?,
+            children,
+            parallel: states.iter().map(State::is_parallel).collect(),
+            history: states.iter().map(State::history).collect(),
+        };
+        for (index, state) in states.iter().enumerate() {
+            // Without a cycle no state has more ancestors than there are states.
+            if hierarchy.ancestors(StateId(index)).nth(states.len()).is_some() {
+                return Err(DefinitionError::ParentCycle {
+                    id: state.id().to_string(),
+                });
+            }
+            if let Some(child) = hierarchy.initial_children[index] {
+                if hierarchy.parents[child.0] != Some(StateId(index)) {
+                    return Err(DefinitionError::InvalidInitialChild {
+                        id: state.id().to_string(),
+                        child_id: states[child.0].id().to_string(),
+                    });
+                }
+            }
+        }
+        Ok(hierarchy)
+    }
+
+    pub(crate) fn parent(&self, state: StateId) -> Option<StateId> {
+        self.parents.get(state.0).copied().flatten()
+    }
+
+    /// The children of `state` other than history pseudo-states.
+    pub(crate) fn children(&self, state: StateId) -> &[StateId] {
+        self.children.get(state.0).map_or(&[], Vec::as_slice)
+    }
+
+    /// `state` followed by its ancestors, innermost first.
+    pub(crate) fn ancestors(&self, state: StateId) -> impl Iterator<Item = StateId> + '_ {
+        std::iter::successors(Some(state), move |state| self.parent(*state))
+    }
+
+    /// Whether `state` or one of its ancestors is `ancestor`.
+    pub(crate) fn is_descendant(&self, state: StateId, ancestor: StateId) -> bool {
+        self.ancestors(state).any(|state| state == ancestor)
+    }
+
+    /// The innermost state that is or contains every one of `states`.
+    pub(crate) fn common_ancestor(
+        &self,
+        states: impl IntoIterator<Item = StateId>,
+    ) -> Option<StateId> {
+        let mut states = states.into_iter();
+        let first = states.next()?;
+        let mut common = self.ancestors(first).collect::<Vec<_>>();
+        for state in states {
+            common.retain(|ancestor| self.is_descendant(state, *ancestor));
+        }
+        common.first().copied()
+    }
+
+    /// The leaves that end up active when `state` is entered on its own.
+    pub(crate) fn initial_leaves(&self, state: StateId) -> Vec<StateId> {
+        let (mut entered, mut leaves) = (Vec::new(), Vec::new());
+        self.enter(state, &[], &mut entered, &mut leaves);
+        leaves
+    }
+
+    pub(crate) fn history(&self, state: StateId) -> Option<HistoryKind> {
+        self.history.get(state.0).copied().flatten()
+    }
+
+    /// The states to enter below `parent` for a `kind` history pseudo-state, given the leaf
+    /// states that were last active below it.
+    pub(crate) fn history_targets(
+        &self,
+        parent: StateId,
+        kind: HistoryKind,
+        last_active: &[StateId],
+    ) -> Vec<StateId> {
+        last_active
+            .iter()
+            .filter(|state| **state != parent && self.is_descendant(**state, parent))
+            .filter_map(|state| match kind {
+                HistoryKind::Shallow => self
+                    .ancestors(*state)
+                    .find(|ancestor| self.parent(*ancestor) == Some(parent)),
+                HistoryKind::Deep => Some(*state),
+            })
+            .collect()
+    }
+
+    /// The states entered by a transition into `target` that stays inside `domain`,
+    /// outermost first, and the leaves that end up active. Below `target` the transition
+    /// heads for `descendants`, or the initial states where it has none to head for.
+    pub(crate) fn entry(
+        &self,
+        domain: Option<StateId>,
+        target: StateId,
+        descendants: &[StateId],
+    ) -> (Vec<StateId>, Vec<StateId>) {
+        let mut targets = descendants.to_vec();
+        targets.push(target);
+        let outermost = self
+            .ancestors(target)
+            .take_while(|state| Some(*state) != domain)
+            .last()
+            .unwrap_or(target);
+        let (mut entered, mut leaves) = (Vec::new(), Vec::new());
+        self.enter(outermost, &targets, &mut entered, &mut leaves);
+        (entered, leaves)
+    }
+
+    /// Enters `state` and every child on the way to one of `targets`. Where no target lies
+    /// below, compound states enter their initial child and parallel states every region.
+    fn enter(
+        &self,
+        state: StateId,
+        targets: &[StateId],
+        entered: &mut Vec<StateId>,
+        leaves: &mut Vec<StateId>,
+    ) {
+        entered.push(state);
+        let children = &self.children[state.0];
+        let towards_target = children
+            .iter()
+            .copied()
+            .find(|child| targets.iter().any(|target| self.is_descendant(*target, *child)));
+        if self.parallel[state.0] {
+            for child in children {
+                self.enter(*child, targets, entered, leaves);
+            }
+        } else if let Some(child) = towards_target.or(self.initial_children[state.0]) {
+            self.enter(child, targets, entered, leaves);
+        } else {
+            leaves.push(state);
+        }
+    }
+
+    /// The active states a transition that stays inside `domain` exits, innermost first,
+    /// and the leaves among them.
+    pub(crate) fn exit(
+        &self,
+        domain: Option<StateId>,
+        leaves: &BTreeSet<StateId>,
+    ) -> (Vec<StateId>, Vec<StateId>) {
+        let exited_leaves = leaves
+            .iter()
+            .copied()
+            .filter(|leaf| {
+                domain.is_none_or(|domain| *leaf != domain && self.is_descendant(*leaf, domain))
+            })
+            .collect::<Vec<_>>();
+        let mut exited = Vec::new();
+        for leaf in &exited_leaves {
+            for state in self.ancestors(*leaf).take_while(|state| Some(*state) != domain) {
+                if !exited.contains(&state) {
+                    exited.push(state);
+                }
+            }
+        }
+        exited.sort_by_key(|state| Reverse(self.ancestors(*state).count()));
+        (exited, exited_leaves)
+    }
+
+    /// The innermost proper ancestor of both `source` and `target` that is not a parallel
+    /// state, or `None` if there is none. Every state below it is exited and entered again
+    /// by the transition.
+    pub(crate) fn domain(&self, source: StateId, target: StateId) -> Option<StateId> {
+        self.ancestors(source)
+            .skip(1)
+            .filter(|state| !self.parallel[state.0])
+            .find(|state| target != *state && self.is_descendant(target, *state))
+    }
+}
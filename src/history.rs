@@ -0,0 +1,274 @@
+use crate::TransitionRecord;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::ops::Index;
+
+/// Where a machine keeps the records of the transitions it takes, see
+/// `StateMachine::set_history_store`.
+pub trait HistoryStore<EventPayload, Context>: Send {
+    /// Keeps `record` as the latest transition, or drops it.
+    fn push(&mut self, record: TransitionRecord<EventPayload, Context>);
+
+    /// The number of records kept.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The record kept at `index`, oldest first.
+    fn get(&self, index: usize) -> Option<&TransitionRecord<EventPayload, Context>>;
+
+    /// Removes and returns the latest record, see `StateMachine::undo`.
+    fn pop(&mut self) -> Option<TransitionRecord<EventPayload, Context>>;
+
+    /// Drops every record kept.
+    fn clear(&mut self);
+
+    /// A store of the same kind keeping copies of the same records, for a clone of the
+    /// machine.
+    fn clone_store<'s>(&self) -> Box<dyn HistoryStore<EventPayload, Context> + 's>
+    where
+        Self: 's,
+        Context: Clone;
+}
+
+impl<'s, EventPayload, Context> dyn HistoryStore<EventPayload, Context> + 's {
+    /// The records kept, oldest first.
+    pub fn iter(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = &TransitionRecord<EventPayload, Context>> {
+        (0..self.len()).filter_map(move |index| self.get(index))
+    }
+}
+
+impl<'s, EventPayload, Context> Index<usize> for dyn HistoryStore<EventPayload, Context> + 's {
+    type Output = TransitionRecord<EventPayload, Context>;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        self.get(index)
+            .unwrap_or_else(|| panic!("No transition record at index: {}", index))
+    }
+}
+
+impl<'s, EventPayload, Context> Debug for dyn HistoryStore<EventPayload, Context> + 's
+where
+    EventPayload: Debug,
+    Context: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Keeps every record. The default store.
+#[derive(Debug)]
+pub struct UnboundedHistory<EventPayload, Context> {
+    records: Vec<TransitionRecord<EventPayload, Context>>,
+}
+
+impl<EventPayload, Context> UnboundedHistory<EventPayload, Context> {
+    pub fn new() -> UnboundedHistory<EventPayload, Context> {
+        UnboundedHistory {
+            records: Vec::new(),
+        }
+    }
+}
+
+impl<EventPayload, Context> Default for UnboundedHistory<EventPayload, Context> {
+    fn default() -> Self {
+        UnboundedHistory::new()
+    }
+}
+
+impl<EventPayload, Context> HistoryStore<EventPayload, Context>
+    for UnboundedHistory<EventPayload, Context>
+where
+    EventPayload: Send + Sync,
+    Context: Send,
+{
+    fn push(&mut self, record: TransitionRecord<EventPayload, Context>) {
+        self.records.push(record);
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn get(&self, index: usize) -> Option<&TransitionRecord<EventPayload, Context>> {
+        self.records.get(index)
+    }
+
+    fn pop(&mut self) -> Option<TransitionRecord<EventPayload, Context>> {
+        self.records.pop()
+    }
+
+    fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    fn clone_store<'s>(&self) -> Box<dyn HistoryStore<EventPayload, Context> + 's>
+    where
+        Self: 's,
+        Context: Clone,
+    {
+        Box::new(UnboundedHistory {
+            records: self.records.clone(),
+        })
+    }
+}
+
+/// Keeps the latest `capacity` records, dropping the oldest one to make room for a new one.
+#[derive(Debug)]
+pub struct RingBufferHistory<EventPayload, Context> {
+    records: VecDeque<TransitionRecord<EventPayload, Context>>,
+    capacity: usize,
+}
+
+impl<EventPayload, Context> RingBufferHistory<EventPayload, Context> {
+    pub fn new(capacity: usize) -> RingBufferHistory<EventPayload, Context> {
+        RingBufferHistory {
+            records: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl<EventPayload, Context> HistoryStore<EventPayload, Context>
+    for RingBufferHistory<EventPayload, Context>
+where
+    EventPayload: Send + Sync,
+    Context: Send,
+{
+    fn push(&mut self, record: TransitionRecord<EventPayload, Context>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn get(&self, index: usize) -> Option<&TransitionRecord<EventPayload, Context>> {
+        self.records.get(index)
+    }
+
+    fn pop(&mut self) -> Option<TransitionRecord<EventPayload, Context>> {
+        self.records.pop_back()
+    }
+
+    fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    fn clone_store<'s>(&self) -> Box<dyn HistoryStore<EventPayload, Context> + 's>
+    where
+        Self: 's,
+        Context: Clone,
+    {
+        Box::new(RingBufferHistory {
+            records: self.records.clone(),
+            capacity: self.capacity,
+        })
+    }
+}
+
+/// Keeps no records.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoHistory;
+
+impl<EventPayload, Context> HistoryStore<EventPayload, Context> for NoHistory {
+    fn push(&mut self, _record: TransitionRecord<EventPayload, Context>) {}
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn get(&self, _index: usize) -> Option<&TransitionRecord<EventPayload, Context>> {
+        None
+    }
+
+    fn pop(&mut self) -> Option<TransitionRecord<EventPayload, Context>> {
+        None
+    }
+
+    fn clear(&mut self) {}
+
+    fn clone_store<'s>(&self) -> Box<dyn HistoryStore<EventPayload, Context> + 's>
+    where
+        Self: 's,
+        Context: Clone,
+    {
+        Box::new(NoHistory)
+    }
+}
+
+/// Keeps every record without the context it was taken from, so the states, edges and
+/// events of the transitions stay around but no `Context` does.
+#[derive(Debug)]
+pub struct IdsOnlyHistory<EventPayload, Context> {
+    records: UnboundedHistory<EventPayload, Context>,
+}
+
+impl<EventPayload, Context> IdsOnlyHistory<EventPayload, Context> {
+    pub fn new() -> IdsOnlyHistory<EventPayload, Context> {
+        IdsOnlyHistory {
+            records: UnboundedHistory::new(),
+        }
+    }
+}
+
+impl<EventPayload, Context> Default for IdsOnlyHistory<EventPayload, Context> {
+    fn default() -> Self {
+        IdsOnlyHistory::new()
+    }
+}
+
+impl<EventPayload, Context> HistoryStore<EventPayload, Context>
+    for IdsOnlyHistory<EventPayload, Context>
+where
+    EventPayload: Send + Sync,
+    Context: Send,
+{
+    fn push(&mut self, mut record: TransitionRecord<EventPayload, Context>) {
+        record.context = None;
+        self.records.push(record);
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn get(&self, index: usize) -> Option<&TransitionRecord<EventPayload, Context>> {
+        self.records.get(index)
+    }
+
+    fn pop(&mut self) -> Option<TransitionRecord<EventPayload, Context>> {
+        self.records.pop()
+    }
+
+    fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    fn clone_store<'s>(&self) -> Box<dyn HistoryStore<EventPayload, Context> + 's>
+    where
+        Self: 's,
+        Context: Clone,
+    {
+        Box::new(IdsOnlyHistory {
+            records: UnboundedHistory {
+                records: self.records.records.clone(),
+            },
+        })
+    }
+}
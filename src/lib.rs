@@ -1,29 +1,248 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fmt::{Debug, Formatter};
-use std::hash::Hash;
-use std::ptr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+mod builder;
+mod dot;
+mod graph;
+mod hierarchy;
+mod history;
+mod queue;
+mod replay;
+mod validation;
+
+pub use builder::{BuildError, StateMachineBuilder};
+pub use history::{HistoryStore, IdsOnlyHistory, NoHistory, RingBufferHistory, UnboundedHistory};
+pub use queue::EventQueue;
+pub use replay::Divergence;
+pub use validation::Diagnostic;
+use hierarchy::Hierarchy;
+use validation::check_ids;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeserializableTransitionRecord<'a, 'b, Context> {
-    from_state_id: &'a str,
-    to_state_id: &'a str,
-    event_id: &'b str,
-    edge_id: &'a str,
-    context: Context,
+pub struct DeserializableTransitionRecord<EventPayload, Context> {
+    from_state_id: String,
+    to_state_id: String,
+    /// The event itself rather than its id, as ids name kinds of events and are reused.
+    #[serde(default = "none", skip_serializing_if = "Option::is_none")]
+    event: Option<Arc<Event<EventPayload>>>,
+    /// Only the id of the event, as records were written before they kept the event. It is
+    /// looked up in the events given to `TransitionRecord::try_hydrate`.
+    #[serde(default = "none", skip_serializing_if = "Option::is_none")]
+    event_id: Option<String>,
+    edge_id: String,
+    /// Missing when the history store dropped it. A context that serializes as `null` is
+    /// still present, see `deserialize_present`.
+    #[serde(
+        default = "none",
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_present",
+        bound(deserialize = "Context: Deserialize<'de>")
+    )]
+    context: Option<Context>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exited_state_ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    continues_dispatch: bool,
+}
+
+impl<EventPayload, Context> DeserializableTransitionRecord<EventPayload, Context> {
+    /// The event the record kept, or the one in `events` with the id it kept.
+    pub(crate) fn find_event(
+        &self,
+        events: &[Arc<Event<EventPayload>>],
+    ) -> Result<Arc<Event<EventPayload>>, HydrationError> {
+        if let Some(event) = &self.event {
+            return Ok(event.clone());
+        }
+        let id = self.event_id.as_deref().unwrap_or_default();
+        events
+            .iter()
+            .find(|event| event.id == id)
+            .cloned()
+            .ok_or_else(|| HydrationError::MissingEvent {
+                id: id.to_string(),
+                field: "event_id",
+            })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DeserializableEdge<'a, Info> {
+pub struct DeserializableEdge<Info> {
     id: String,
-    from_state_id: &'a str,
-    to_state_id: &'a str,
+    from_state_id: String,
+    to_state_id: String,
     info: Info,
+    #[serde(default, skip_serializing_if = "is_default")]
+    priority: i32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    triggers: Vec<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    eventless: bool,
+}
+
+impl<Info> DeserializableEdge<Info> {
+    pub fn new(
+        id: impl Into<String>,
+        from_state_id: impl Into<String>,
+        to_state_id: impl Into<String>,
+        info: Info,
+    ) -> DeserializableEdge<Info> {
+        DeserializableEdge {
+            id: id.into(),
+            from_state_id: from_state_id.into(),
+            to_state_id: to_state_id.into(),
+            info,
+            priority: 0,
+            triggers: Vec::new(),
+            eventless: false,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> DeserializableEdge<Info> {
+        self.priority = priority;
+        self
+    }
+
+    /// See `Edge::with_triggers`.
+    pub fn with_triggers(
+        mut self,
+        event_ids: impl IntoIterator<Item = impl Into<String>>,
+    ) -> DeserializableEdge<Info> {
+        self.triggers = event_ids.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// See `Edge::with_eventless`.
+    pub fn with_eventless(mut self) -> DeserializableEdge<Info> {
+        self.eventless = true;
+        self
+    }
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+fn none<T>() -> Option<T> {
+    None
 }
 
+/// Deserializes a field that is present as `Some`, whatever it holds, leaving `None` for a
+/// missing field.
+fn deserialize_present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Handle to a state: its index in the machine's `states`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct StateId(pub usize);
+
+/// Handle to an edge: its index in the machine's `edges`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct EdgeId(pub usize);
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub struct State {
     id: String,
+    /// The id of the compound state this state is nested in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    /// The id of the child entered when this state is entered directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    initial: Option<String>,
+    /// Whether every child of this state is an orthogonal region, active at the same time
+    /// as its siblings.
+    #[serde(default, skip_serializing_if = "is_default")]
+    parallel: bool,
+    /// Makes this a history pseudo-state of its parent, which is never active itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    history: Option<HistoryKind>,
+    /// Entering a final state without a parent finishes the machine.
+    #[serde(rename = "final", default, skip_serializing_if = "is_default")]
+    final_state: bool,
+}
+
+/// What a transition into a history pseudo-state restores of the states last active in the
+/// history state's parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HistoryKind {
+    /// The child of the parent that was last active, entered at its initial state.
+    Shallow,
+    /// The leaf states that were last active anywhere below the parent.
+    Deep,
+}
+
+impl State {
+    pub fn new(id: impl Into<String>) -> State {
+        State {
+            id: id.into(),
+            parent: None,
+            initial: None,
+            parallel: false,
+            history: None,
+            final_state: false,
+        }
+    }
+
+    pub fn with_parent(mut self, parent_id: impl Into<String>) -> State {
+        self.parent = Some(parent_id.into());
+        self
+    }
+
+    pub fn with_initial(mut self, child_id: impl Into<String>) -> State {
+        self.initial = Some(child_id.into());
+        self
+    }
+
+    /// Makes every child of this state a region that is entered along with this state.
+    pub fn with_parallel_regions(mut self) -> State {
+        self.parallel = true;
+        self
+    }
+
+    /// Makes this a history pseudo-state of its parent. An edge into it enters the parent
+    /// where it was last left, or at its initial state if it was never active.
+    pub fn with_history(mut self, kind: HistoryKind) -> State {
+        self.history = Some(kind);
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn parent(&self) -> Option<&str> {
+        self.parent.as_deref()
+    }
+
+    pub fn initial(&self) -> Option<&str> {
+        self.initial.as_deref()
+    }
+
+    pub fn is_parallel(&self) -> bool {
+        self.parallel
+    }
+
+    pub fn history(&self) -> Option<HistoryKind> {
+        self.history
+    }
+
+    /// Marks this state as final, see `StateMachine::is_finished`.
+    pub fn with_final(mut self) -> State {
+        self.final_state = true;
+        self
+    }
+
+    pub fn is_final(&self) -> bool {
+        self.final_state
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,570 +251,3421 @@ pub struct Event<EventPayload> {
     payload: EventPayload,
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct Edge<'a, EdgeInfo> {
+impl<EventPayload> Event<EventPayload> {
+    pub fn new(id: impl Into<String>, payload: EventPayload) -> Event<EventPayload> {
+        Event {
+            id: id.into(),
+            payload,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn payload(&self) -> &EventPayload {
+        &self.payload
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Edge<EdgeInfo> {
     id: String,
-    from_state: &'a State,
-    to_state: &'a State,
+    from_state: StateId,
+    to_state: StateId,
     info: EdgeInfo,
+    /// Used by `ConflictPolicy::Priority`; higher wins.
+    #[serde(default)]
+    priority: i32,
+    /// The ids of the events this edge is considered for; empty means every event.
+    #[serde(default)]
+    triggers: Vec<String>,
+    /// Whether this edge is taken without an event, see `with_eventless`.
+    #[serde(default)]
+    eventless: bool,
 }
 
-impl<'a, EdgeInfo> Edge<'a, EdgeInfo> {
-    pub fn hydrate(
-        deserializable_edge: DeserializableEdge<'a, EdgeInfo>,
-        states: Vec<&'a State>,
-    ) -> Edge<'a, EdgeInfo> {
-        let from_state = states
-            .iter()
-            .find(|state| state.id == deserializable_edge.from_state_id)
-            .expect(
-                format!(
-                    "Could not find a state with id: {}",
-                    deserializable_edge.from_state_id
-                )
-                .as_str(),
-            );
-        let to_state = states
-            .iter()
-            .find(|state| state.id == deserializable_edge.to_state_id)
-            .expect(
-                format!(
-                    "Could not find a state with id: {}",
-                    deserializable_edge.to_state_id
-                )
-                .as_str(),
-            );
+/// A reference by id that could not be resolved while hydrating. `field` names the
+/// deserializable field the id was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HydrationError {
+    MissingState { id: String, field: &'static str },
+    MissingEdge { id: String, field: &'static str },
+    MissingEvent { id: String, field: &'static str },
+}
+
+impl Display for HydrationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HydrationError::MissingState { id, field } => {
+                write!(f, "Could not find a state with id: {} ({})", id, field)
+            }
+            HydrationError::MissingEdge { id, field } => {
+                write!(f, "Could not find an edge with id: {} ({})", id, field)
+            }
+            HydrationError::MissingEvent { id, field } => {
+                write!(f, "Could not find an event with id: {} ({})", id, field)
+            }
+        }
+    }
+}
+
+impl Error for HydrationError {}
+
+fn find_state(states: &[State], id: &str, field: &'static str) -> Result<StateId, HydrationError> {
+    states
+        .iter()
+        .position(|state| state.id == id)
+        .map(StateId)
+        .ok_or_else(|| HydrationError::MissingState {
+            id: id.to_string(),
+            field,
+        })
+}
+
+impl<EdgeInfo> Edge<EdgeInfo> {
+    pub fn new(
+        id: impl Into<String>,
+        from_state: StateId,
+        to_state: StateId,
+        info: EdgeInfo,
+    ) -> Edge<EdgeInfo> {
         Edge {
+            id: id.into(),
+            from_state,
+            to_state,
+            info,
+            priority: 0,
+            triggers: Vec::new(),
+            eventless: false,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Edge<EdgeInfo> {
+        self.priority = priority;
+        self
+    }
+
+    /// Restricts this edge to events with one of `event_ids`, so the machine only asks its
+    /// guard or the handler about those events.
+    pub fn with_triggers(
+        mut self,
+        event_ids: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Edge<EdgeInfo> {
+        self.triggers = event_ids.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Makes this an eventless edge, which is never considered for a dispatched event.
+    /// Instead it is taken as soon as its guard or the handler accepts it after a transition,
+    /// see `StateMachine::try_dispatch`. Its triggers are ignored.
+    pub fn with_eventless(mut self) -> Edge<EdgeInfo> {
+        self.eventless = true;
+        self
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    pub fn triggers(&self) -> &[String] {
+        &self.triggers
+    }
+
+    pub fn is_eventless(&self) -> bool {
+        self.eventless
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn from_state(&self) -> StateId {
+        self.from_state
+    }
+
+    pub fn to_state(&self) -> StateId {
+        self.to_state
+    }
+
+    pub fn info(&self) -> &EdgeInfo {
+        &self.info
+    }
+
+    pub fn hydrate(
+        deserializable_edge: DeserializableEdge<EdgeInfo>,
+        states: &[State],
+    ) -> Edge<EdgeInfo> {
+        Edge::try_hydrate(deserializable_edge, states).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_hydrate(
+        deserializable_edge: DeserializableEdge<EdgeInfo>,
+        states: &[State],
+    ) -> Result<Edge<EdgeInfo>, HydrationError> {
+        let from_state = find_state(states, &deserializable_edge.from_state_id, "from_state_id")?;
+        let to_state = find_state(states, &deserializable_edge.to_state_id, "to_state_id")?;
+        Ok(Edge {
             id: deserializable_edge.id,
             from_state,
             to_state,
             info: deserializable_edge.info,
-        }
+            priority: deserializable_edge.priority,
+            triggers: deserializable_edge.triggers,
+            eventless: deserializable_edge.eventless,
+        })
     }
-}
 
-impl<'a, Info> From<Edge<'a, Info>> for DeserializableEdge<'a, Info> {
-    fn from(edge: Edge<'a, Info>) -> Self {
+    /// The inverse of `hydrate`. `states` must be the states this edge's ids index into.
+    pub fn dehydrate(self, states: &[State]) -> DeserializableEdge<EdgeInfo> {
         DeserializableEdge {
-            id: edge.id,
-            from_state_id: &edge.from_state.id,
-            to_state_id: &edge.to_state.id,
-            info: edge.info,
+            id: self.id,
+            from_state_id: states[self.from_state.0].id.clone(),
+            to_state_id: states[self.to_state.0].id.clone(),
+            info: self.info,
+            priority: self.priority,
+            triggers: self.triggers,
+            eventless: self.eventless,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct TransitionRecord<'a, 'b, EventPayload, EdgeInfo, Context> {
-    from_state: &'a State,
-    to_state: &'a State,
-    event: &'b Event<EventPayload>,
-    edge: &'a Edge<'a, EdgeInfo>,
-    context: Context,
+#[derive(Debug, Serialize)]
+pub struct TransitionRecord<EventPayload, Context> {
+    from_state: StateId,
+    to_state: StateId,
+    event: Arc<Event<EventPayload>>,
+    edge: EdgeId,
+    /// The context the transition was taken from, unless the history store dropped it.
+    context: Option<Context>,
+    /// The leaf states the transition exited, when there was more than `from_state`.
+    exited_states: Vec<StateId>,
+    /// Whether the transition was taken by the same dispatch as the one before it.
+    continues_dispatch: bool,
 }
 
-impl<'a, 'b, EventPayload, EdgeInfo, Context>
-TransitionRecord<'a, 'b, EventPayload, EdgeInfo, Context>
-{
-    pub fn hydrate(
-        deserializable_transition_record: DeserializableTransitionRecord<'a, 'b, Context>,
-        states: Vec<&'a State>,
-        edges: Vec<&'a Edge<'a, EdgeInfo>>,
-        events: Vec<&'b Event<EventPayload>>,
-    ) -> TransitionRecord<'a, 'b, EventPayload, EdgeInfo, Context> {
-        let from_state = states
-            .iter()
-            .find(|state| state.id == deserializable_transition_record.from_state_id)
-            .expect(
-                format!(
-                    "Could not find a state with id: {}",
-                    deserializable_transition_record.from_state_id
-                )
-                .as_str(),
-            );
-        let to_state = states
-            .iter()
-            .find(|state| state.id == deserializable_transition_record.to_state_id)
-            .expect(
-                format!(
-                    "Could not find a state with id: {}",
-                    deserializable_transition_record.to_state_id
-                )
-                .as_str(),
-            );
-        let event = events
-            .iter()
-            .find(|edge| edge.id == deserializable_transition_record.event_id)
-            .expect(
-                format!(
-                    "Could not find an event with id: {}",
-                    deserializable_transition_record.event_id
-                )
-                .as_str(),
-            );
+impl<EventPayload, Context: Clone> Clone for TransitionRecord<EventPayload, Context> {
+    fn clone(&self) -> Self {
+        TransitionRecord {
+            from_state: self.from_state,
+            to_state: self.to_state,
+            event: self.event.clone(),
+            edge: self.edge,
+            context: self.context.clone(),
+            exited_states: self.exited_states.clone(),
+            continues_dispatch: self.continues_dispatch,
+        }
+    }
+}
+
+impl<EventPayload, Context> TransitionRecord<EventPayload, Context> {
+    pub fn hydrate<EdgeInfo>(
+        deserializable_transition_record: DeserializableTransitionRecord<EventPayload, Context>,
+        states: &[State],
+        edges: &[Edge<EdgeInfo>],
+        events: &[Arc<Event<EventPayload>>],
+    ) -> TransitionRecord<EventPayload, Context> {
+        TransitionRecord::try_hydrate(deserializable_transition_record, states, edges, events)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// `events` are only looked up for records that kept the id of their event rather than
+    /// the event.
+    pub fn try_hydrate<EdgeInfo>(
+        deserializable_transition_record: DeserializableTransitionRecord<EventPayload, Context>,
+        states: &[State],
+        edges: &[Edge<EdgeInfo>],
+        events: &[Arc<Event<EventPayload>>],
+    ) -> Result<TransitionRecord<EventPayload, Context>, HydrationError> {
+        let from_state = find_state(
+            states,
+            &deserializable_transition_record.from_state_id,
+            "from_state_id",
+        )?;
+        let to_state = find_state(
+            states,
+            &deserializable_transition_record.to_state_id,
+            "to_state_id",
+        )?;
+        let event = deserializable_transition_record.find_event(events)?;
         let edge = edges
             .iter()
-            .find(|edge| edge.id == deserializable_transition_record.edge_id)
-            .expect(
-                format!(
-                    "Could not find an edge with id: {}",
-                    deserializable_transition_record.edge_id
-                )
-                .as_str(),
-            );
-        TransitionRecord {
+            .position(|edge| edge.id == deserializable_transition_record.edge_id)
+            .map(EdgeId)
+            .ok_or_else(|| HydrationError::MissingEdge {
+                id: deserializable_transition_record.edge_id.clone(),
+                field: "edge_id",
+            })?;
+        let exited_states = deserializable_transition_record
+            .exited_state_ids
+            .iter()
+            .map(|id| find_state(states, id, "exited_state_ids"))
+            .collect::<Result<_, _>>()?;
+        Ok(TransitionRecord {
             from_state,
             to_state,
             event,
             edge,
             context: deserializable_transition_record.context,
+            exited_states,
+            continues_dispatch: deserializable_transition_record.continues_dispatch,
+        })
+    }
+
+    pub fn from_state(&self) -> StateId {
+        self.from_state
+    }
+
+    pub fn to_state(&self) -> StateId {
+        self.to_state
+    }
+
+    pub fn event(&self) -> &Arc<Event<EventPayload>> {
+        &self.event
+    }
+
+    pub fn edge(&self) -> EdgeId {
+        self.edge
+    }
+
+    pub fn context(&self) -> Option<&Context> {
+        self.context.as_ref()
+    }
+
+    /// Whether the transition was taken by the same dispatch as the one before it, rather
+    /// than being the first one a dispatch took.
+    pub fn continues_dispatch(&self) -> bool {
+        self.continues_dispatch
+    }
+
+    /// The leaf states that were active before this transition and left by it.
+    pub(crate) fn exited_leaves(&self) -> &[StateId] {
+        if self.exited_states.is_empty() {
+            std::slice::from_ref(&self.from_state)
+        } else {
+            &self.exited_states
+        }
+    }
+
+    /// The inverse of `hydrate`. `states` and `edges` must be the ones this record's ids
+    /// index into.
+    pub fn dehydrate<EdgeInfo>(
+        self,
+        states: &[State],
+        edges: &[Edge<EdgeInfo>],
+    ) -> DeserializableTransitionRecord<EventPayload, Context> {
+        DeserializableTransitionRecord {
+            from_state_id: states[self.from_state.0].id.clone(),
+            to_state_id: states[self.to_state.0].id.clone(),
+            event: Some(self.event),
+            event_id: None,
+            edge_id: edges[self.edge.0].id.clone(),
+            context: self.context,
+            exited_state_ids: self
+                .exited_states
+                .iter()
+                .map(|state| states[state.0].id.clone())
+                .collect(),
+            continues_dispatch: self.continues_dispatch,
         }
     }
 }
 
-impl<'a, 'b, EventPayload, EdgeInfo, Context>
-    From<TransitionRecord<'a, 'b, EventPayload, EdgeInfo, Context>>
-    for DeserializableTransitionRecord<'a, 'b, Context>
-{
-    fn from(state_transition: TransitionRecord<'a, 'b, EventPayload, EdgeInfo, Context>) -> Self {
-        DeserializableTransitionRecord::<'a, 'b, Context> {
-            from_state_id: &state_transition.from_state.id,
-            to_state_id: &state_transition.to_state.id,
-            event_id: &state_transition.event.id,
-            edge_id: &state_transition.edge.id,
-            context: state_transition.context,
+/// An owned, serializable description of a machine's topology.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MachineDefinition<EdgeInfo> {
+    pub initial_state_id: String,
+    pub states: Vec<State>,
+    pub edges: Vec<DeserializableEdge<EdgeInfo>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DefinitionError {
+    DuplicateState { id: String },
+    DuplicateEdge { id: String },
+    UnknownInitialState { id: String },
+    /// Following the parents of state `id` leads back to it.
+    ParentCycle { id: String },
+    /// The initial child of state `id` is not nested in it.
+    InvalidInitialChild { id: String, child_id: String },
+    /// The history pseudo-state `id` has no parent to restore.
+    HistoryWithoutParent { id: String },
+    Hydration(HydrationError),
+}
+
+impl Display for DefinitionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DefinitionError::DuplicateState { id } => {
+                write!(f, "Duplicate state id: {}", id)
+            }
+            DefinitionError::DuplicateEdge { id } => write!(f, "Duplicate edge id: {}", id),
+            DefinitionError::UnknownInitialState { id } => {
+                write!(f, "Could not find the initial state with id: {}", id)
+            }
+            DefinitionError::ParentCycle { id } => write!(f, "State {} is its own ancestor", id),
+            DefinitionError::InvalidInitialChild { id, child_id } => write!(
+                f,
+                "Initial state {} of state {} is not one of its children",
+                child_id, id
+            ),
+            DefinitionError::HistoryWithoutParent { id } => {
+                write!(f, "History state {} has no parent", id)
+            }
+            DefinitionError::Hydration(error) => Display::fmt(error, f),
+        }
+    }
+}
+
+impl Error for DefinitionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DefinitionError::Hydration(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<HydrationError> for DefinitionError {
+    fn from(error: HydrationError) -> Self {
+        DefinitionError::Hydration(error)
+    }
+}
+
+/// The initial state, states, edges and state hierarchy of a `MachineDefinition` after
+/// resolving ids.
+type HydratedDefinition<EdgeInfo> = (StateId, Vec<State>, Vec<Edge<EdgeInfo>>, Hierarchy);
+
+impl<EdgeInfo> MachineDefinition<EdgeInfo> {
+    pub fn validate(&self) -> Result<(), DefinitionError> {
+        self.check().map(|_| ())
+    }
+
+    /// Checks the definition like `validate`, returning the hierarchy it resolved.
+    fn check(&self) -> Result<Hierarchy, DefinitionError> {
+        // An unknown initial state is checked as the index past the last state.
+        let initial_state = find_state(&self.states, &self.initial_state_id, "initial_state_id")
+            .unwrap_or(StateId(self.states.len()));
+        let edge_ids = self.edges.iter().map(|edge| edge.id.as_str());
+        let diagnostics = check_ids(&self.states, edge_ids, initial_state);
+        if let Some(diagnostic) = diagnostics.into_iter().next() {
+            return Err(match diagnostic {
+                Diagnostic::DuplicateState { id } => DefinitionError::DuplicateState { id },
+                Diagnostic::DuplicateEdge { id } => DefinitionError::DuplicateEdge { id },
+                _ => DefinitionError::UnknownInitialState {
+                    id: self.initial_state_id.clone(),
+                },
+            });
         }
+        let state_ids = self
+            .states
+            .iter()
+            .map(|state| state.id.as_str())
+            .collect::<HashSet<_>>();
+        for edge in &self.edges {
+            for (id, field) in [
+                (&edge.from_state_id, "from_state_id"),
+                (&edge.to_state_id, "to_state_id"),
+            ] {
+                if !state_ids.contains(id.as_str()) {
+                    return Err(HydrationError::MissingState {
+                        id: id.clone(),
+                        field,
+                    }
+                    .into());
+                }
+            }
+        }
+        Hierarchy::resolve(&self.states)
+    }
+
+    /// Validates the definition and resolves its string ids into the initial state, states
+    /// and edges of a machine.
+    fn hydrate(self) -> Result<HydratedDefinition<EdgeInfo>, DefinitionError> {
+        let hierarchy = self.check()?;
+        let MachineDefinition {
+            initial_state_id,
+            states,
+            edges,
+        } = self;
+        let initial_state = find_state(&states, &initial_state_id, "initial_state_id")?;
+        let edges = edges
+            .into_iter()
+            .map(|edge| Edge::try_hydrate(edge, &states))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((initial_state, states, edges, hierarchy))
     }
 }
 
+/// The running state of a machine, by id, so it can be persisted and later restored
+/// against the same `MachineDefinition`. The history keeps the events it was taken for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MachineSnapshot<EventPayload, Context> {
+    pub current_state_id: Option<String>,
+    /// The active leaf states, needed to restore a machine with parallel regions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub active_state_ids: Vec<String>,
+    /// The leaf states last active below each compound state that is no longer active, for
+    /// transitions into its history pseudo-states.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub last_active_state_ids: BTreeMap<String, Vec<String>>,
+    pub current_context: Context,
+    pub transition_history: Vec<DeserializableTransitionRecord<EventPayload, Context>>,
+}
+
 pub type EventHandler<EventPayload, EdgeInfo, Context> =
     fn(&Event<EventPayload>, &Edge<EdgeInfo>, &Context) -> Option<Context>;
 
-pub struct StateMachine<'a, 'b, EventPayload, EdgeInfo, Context> {
-    pub transition_history: Vec<TransitionRecord<'a, 'b, EventPayload, EdgeInfo, Context>>,
-    pub current_state: Option<&'a State>,
-    pub current_context: Context,
-    pub states: Vec<&'a State>,
-    pub edges: Vec<&'a Edge<'a, EdgeInfo>>,
-    state_to_edge_map: HashMap<&'a State, Vec<&'a Edge<'a, EdgeInfo>>>,
-    event_handler: &'a EventHandler<EventPayload, EdgeInfo, Context>,
-    start_dispatch_hook: Option<Box<dyn for<'c> FnMut(
+/// Decides whether `edge` accepts `event`, returning the context to transition with.
+///
+/// Implemented for every matching `Fn`, including `EventHandler` and boxed closures, so
+/// implement it directly only for handlers that carry their own state.
+pub trait Handler<EventPayload, EdgeInfo, Context>: Send + Sync {
+    fn handle(
+        &self,
+        event: &Event<EventPayload>,
+        edge: &Edge<EdgeInfo>,
+        context: &Context,
+    ) -> Option<Context>;
+}
+
+impl<F, EventPayload, EdgeInfo, Context> Handler<EventPayload, EdgeInfo, Context> for F
+where
+    F: Fn(&Event<EventPayload>, &Edge<EdgeInfo>, &Context) -> Option<Context> + Send + Sync,
+{
+    fn handle(
+        &self,
+        event: &Event<EventPayload>,
+        edge: &Edge<EdgeInfo>,
+        context: &Context,
+    ) -> Option<Context> {
+        self(event, edge, context)
+    }
+}
+
+type SharedHandler<'a, EventPayload, EdgeInfo, Context> =
+    Arc<dyn Handler<EventPayload, EdgeInfo, Context> + 'a>;
+
+/// An edge chosen to take an event, with the context the handler built for it and the
+/// conflict policy that chose it.
+type Selection<Context> = (EdgeId, Option<Context>, Option<ConflictPolicyKind>);
+
+/// Decides whether an edge accepts an event, without building the new context.
+pub type Guard<'a, EventPayload, Context> =
+    dyn Fn(&Event<EventPayload>, &Context) -> bool + Send + Sync + 'a;
+
+/// Builds the new context once an edge has been chosen to take an event.
+pub type Action<'a, EventPayload, Context> =
+    dyn Fn(&Event<EventPayload>, &Context) -> Context + Send + Sync + 'a;
+
+/// The guard and action registered for a single edge, which take precedence over the
+/// machine's `Handler` for that edge.
+pub(crate) struct EdgeTransition<'a, EventPayload, Context> {
+    guard: Option<Arc<Guard<'a, EventPayload, Context>>>,
+    action: TransitionAction<'a, EventPayload, Context>,
+}
+
+pub(crate) enum TransitionAction<'a, EventPayload, Context> {
+    Run(Arc<Action<'a, EventPayload, Context>>),
+    /// A guard without an action keeps the current context, copied with this function.
+    KeepContext(fn(&Context) -> Context),
+}
+
+impl<'a, EventPayload, Context> Clone for EdgeTransition<'a, EventPayload, Context> {
+    fn clone(&self) -> Self {
+        EdgeTransition {
+            guard: self.guard.clone(),
+            action: match &self.action {
+                TransitionAction::Run(action) => TransitionAction::Run(action.clone()),
+                TransitionAction::KeepContext(copy) => TransitionAction::KeepContext(*copy),
+            },
+        }
+    }
+}
+
+pub type DispatchHook<'a, EventPayload, EdgeInfo, Context> = dyn for<'c> FnMut(
         &'c Event<EventPayload>,
         &'c State,
         &'c Context,
-        &'c Vec<&'a State>,
-        &'c Vec<&'a Edge<'a, EdgeInfo>>
-    ) + 'a>>,
-    end_dispatch_hook: Option<Box<dyn for<'c> FnMut(
+        &'c Vec<State>,
+        &'c Vec<Edge<EdgeInfo>>,
+    ) + Send + 'a;
+
+pub type StateHook<'a, EventPayload, EdgeInfo, Context> = dyn for<'c> FnMut(
         &'c Event<EventPayload>,
         &'c State,
+        &'c Edge<EdgeInfo>,
         &'c Context,
-        &'c Vec<&'a State>,
-        &'c Vec<&'a Edge<'a, EdgeInfo>>
-    ) + 'a>>,
-    // on_state_entry_hook: Option<Box<dyn for<'c> FnMut(
-    //     &'c Event<EventPayload>,
-    //     &'c State,
-    //     &'c Edge<'a, EdgeInfo>,
-    //     &'c Context,
-    //     &'c Vec<&'a State>,
-    //     &'c Vec<&'a Edge<'a, EdgeInfo>>
-    // ) + 'a>>,
-    // on_state_exit_hook: Option<Box<dyn for<'c> FnMut(
-    //     &'c Event<EventPayload>,
-    //     &'c State,
-    //     &'c Edge<'a, EdgeInfo>,
-    //     &'c Context,
-    //     &'c Vec<&'a State>,
-    //     &'c Vec<&'a Edge<'a, EdgeInfo>>
-    // ) + 'a>>,
-    on_edge_traversal_hook: Option<Box<dyn for<'c> FnMut(
+        &'c Vec<State>,
+        &'c Vec<Edge<EdgeInfo>>,
+    ) + Send + 'a;
+
+pub type EdgeTraversalHook<'a, EventPayload, EdgeInfo, Context> = dyn for<'c> FnMut(
         &'c Event<EventPayload>,
-        &'c Edge<'a, EdgeInfo>,
+        &'c Edge<EdgeInfo>,
         &'c Context,
-        &'c Vec<&'a State>,
-        &'c Vec<&'a Edge<'a, EdgeInfo>>
-    ) + 'a>>,
+        &'c Vec<State>,
+        &'c Vec<Edge<EdgeInfo>>,
+    ) + Send + 'a;
+
+/// Hooks are shared between clones of a machine.
+type SharedHook<T> = Arc<Mutex<T>>;
+
+/// Hooks attached to a single state or edge.
+type HookMap<K, T> = HashMap<K, Vec<SharedHook<T>>>;
+
+/// A hook that panicked has already reported its panic; later calls still go through.
+fn lock<T: ?Sized>(hook: &Mutex<T>) -> MutexGuard<'_, T> {
+    hook.lock().unwrap_or_else(|error| error.into_inner())
 }
 
-impl<'a, 'b, EventPayload, EdgeInfo, Context> Debug
-    for StateMachine<'a, 'b, EventPayload, EdgeInfo, Context>
-where
-    EventPayload: Debug,
-    EdgeInfo: Debug,
-    Context: Debug,
-{
+pub type ConflictResolver<'a, EdgeInfo> =
+    dyn Fn(&[&Edge<EdgeInfo>]) -> Option<usize> + Send + Sync + 'a;
+
+/// How `try_dispatch` picks an edge when more than one edge accepts an event.
+#[derive(Clone)]
+pub enum ConflictPolicy<'a, EdgeInfo> {
+    /// Fail with `DispatchError::AmbiguousTransition`.
+    Reject,
+    /// Take the accepting edge that was declared first.
+    FirstMatch,
+    /// Take the accepting edge with the highest `priority`. A tie for the highest priority
+    /// is rejected.
+    Priority,
+    /// Return the index of the edge to take out of the accepting edges, or `None` to reject.
+    Custom(Arc<ConflictResolver<'a, EdgeInfo>>),
+}
+
+impl<'a, EdgeInfo> ConflictPolicy<'a, EdgeInfo> {
+    pub fn kind(&self) -> ConflictPolicyKind {
+        match self {
+            ConflictPolicy::Reject => ConflictPolicyKind::Reject,
+            ConflictPolicy::FirstMatch => ConflictPolicyKind::FirstMatch,
+            ConflictPolicy::Priority => ConflictPolicyKind::Priority,
+            ConflictPolicy::Custom(_) => ConflictPolicyKind::Custom,
+        }
+    }
+}
+
+impl<'a, EdgeInfo> Debug for ConflictPolicy<'a, EdgeInfo> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("StateMachine")
-            .field("current_state", &self.current_state)
-            .field("transition_history", &self.transition_history)
-            .finish()
+        Debug::fmt(&self.kind(), f)
     }
 }
 
-impl<'a, 'b, EventPayload, EdgeInfo, Context> StateMachine<'a, 'b, EventPayload, EdgeInfo, Context>
-where
-    EventPayload: Debug,
-    EdgeInfo: Debug,
-    Context: Debug,
-{
-    pub fn new(
-        initial_state: &'a State,
-        initial_context: Context,
-        states: Vec<&'a State>,
-        edges: Vec<&'a Edge<'a, EdgeInfo>>,
-        event_handler: &'a EventHandler<EventPayload, EdgeInfo, Context>,
-        start_dispatch_hook: Option<impl for<'c> FnMut(
-            &'c Event<EventPayload>,
-            &'c State,
-            &'c Context,
-            &'c Vec<&'a State>,
-            &'c Vec<&'a Edge<'a, EdgeInfo>>
-        ) + 'a>,
-        end_dispatch_hook: Option<impl for<'c> FnMut(
-            &'c Event<EventPayload>,
-            &'c State,
-            &'c Context,
-            &'c Vec<&'a State>,
-            &'c Vec<&'a Edge<'a, EdgeInfo>>
-        ) + 'a>,
-        // on_state_entry_hook: Option<impl for<'c> FnMut(
-        //     &'c Event<EventPayload>,
-        //     &'c State,
-        //     &'c Edge<'a, EdgeInfo>,
-        //     &'c Context,
-        //     &'c Vec<&'a State>,
-        //     &'c Vec<&'a Edge<'a, EdgeInfo>>
-        // ) + 'a>,
-        // on_state_exit_hook: Option<impl for<'c> FnMut(
-        //     &'c Event<EventPayload>,
-        //     &'c State,
-        //     &'c Edge<'a, EdgeInfo>,
-        //     &'c Context,
-        //     &'c Vec<&'a State>,
-        //     &'c Vec<&'a Edge<'a, EdgeInfo>>
-        // ) + 'a>,
-        on_edge_traversal_hook: Option<impl for<'c> FnMut(
-            &'c Event<EventPayload>,
-            &'c Edge<'a, EdgeInfo>,
-            &'c Context,
-            &'c Vec<&'a State>,
-            &'c Vec<&'a Edge<'a, EdgeInfo>>
-        ) + 'a>,
-    ) -> StateMachine<'a, 'b, EventPayload, EdgeInfo, Context> {
-        let mut state_to_edge_map = HashMap::new();
-        for state in states.clone() {
-            let mut state_edges = Vec::new();
-            for edge in &edges {
-                if ptr::eq(edge.from_state, state) {
-                    state_edges.push(edge.clone());
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConflictPolicyKind {
+    Reject,
+    FirstMatch,
+    Priority,
+    Custom,
+}
+
+/// The result of a successful `StateMachine::try_dispatch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DispatchOutcome {
+    pub previous_state: StateId,
+    pub new_state: StateId,
+    /// The edge that was traversed, or `None` if no edge accepted the event. With parallel
+    /// regions this is the first of the edges taken, `transition_history` has all of them.
+    /// If the event itself was not accepted, this is the first edge taken for a raised event.
+    pub edge: Option<EdgeId>,
+    /// The conflict policy that chose `edge`, if more than one edge accepted the event.
+    pub resolved_by: Option<ConflictPolicyKind>,
+}
+
+impl DispatchOutcome {
+    pub fn transitioned(&self) -> bool {
+        self.edge.is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DispatchError {
+    /// More than one edge leaving `state_id` accepted the event.
+    AmbiguousTransition {
+        state_id: String,
+        edge_ids: Vec<String>,
+    },
+    /// The current state is not one of the states the machine was built with.
+    UnknownState { state: StateId },
+    NoCurrentState,
+    /// The machine reached the final state `state_id` and accepts no more events.
+    Finished { state_id: String },
+    /// Eventless edges were still being taken from state `state_id` after `limit` steps.
+    EventlessLoop { state_id: String, limit: usize },
+    /// `error` stopped a dispatch after it had taken edges, which are kept; `outcome` is
+    /// where they left the machine.
+    PartlyApplied {
+        outcome: DispatchOutcome,
+        error: Box<DispatchError>,
+    },
+}
+
+impl Display for DispatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DispatchError::AmbiguousTransition { state_id, edge_ids } => write!(
+                f,
+                "Cannot have multiple transitioning edges from state {}: {}",
+                state_id,
+                edge_ids.join(", ")
+            ),
+            DispatchError::UnknownState { state } => {
+                write!(f, "Could not find a state with index: {}", state.0)
+            }
+            DispatchError::NoCurrentState => write!(f, "State machine has no current state"),
+            DispatchError::Finished { state_id } => {
+                write!(f, "State machine has finished in state {}", state_id)
+            }
+            DispatchError::EventlessLoop { state_id, limit } => write!(
+                f,
+                "Eventless transitions from state {} did not settle within {} steps",
+                state_id, limit
+            ),
+            DispatchError::PartlyApplied { error, .. } => {
+                write!(f, "Dispatch failed after taking transitions: {}", error)
+            }
+        }
+    }
+}
+
+impl Error for DispatchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DispatchError::PartlyApplied { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RewindError {
+    /// `transition_history` has no record at `index`; it has `len` records.
+    OutOfRange { index: usize, len: usize },
+    /// The record at `index` was kept without the context it was taken from, see
+    /// `IdsOnlyHistory`.
+    MissingContext { index: usize },
+    NothingToUndo,
+    NothingToRedo,
+}
+
+impl Display for RewindError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RewindError::OutOfRange { index, len } => write!(
+                f,
+                "Could not find a transition record at index {} of {}",
+                index, len
+            ),
+            RewindError::MissingContext { index } => {
+                write!(f, "Transition record at index {} has no context", index)
+            }
+            RewindError::NothingToUndo => write!(f, "No transition to undo"),
+            RewindError::NothingToRedo => write!(f, "No undone transition to redo"),
+        }
+    }
+}
+
+impl Error for RewindError {}
+
+/// A transition taken back by `StateMachine::rewind_to`, with the active leaf states and
+/// context it left the machine in, so `StateMachine::redo` can take it again.
+struct UndoneTransition<EventPayload, Context> {
+    record: TransitionRecord<EventPayload, Context>,
+    active_states: BTreeSet<StateId>,
+    context: Context,
+}
+
+impl<EventPayload, Context: Clone> Clone for UndoneTransition<EventPayload, Context> {
+    fn clone(&self) -> Self {
+        UndoneTransition {
+            record: self.record.clone(),
+            active_states: self.active_states.clone(),
+            context: self.context.clone(),
+        }
+    }
+}
+
+/// The edges leaving a single state, by the event ids that trigger them.
+#[derive(Debug, Clone, Default)]
+struct TriggerIndex {
+    by_event_id: HashMap<String, Vec<EdgeId>>,
+    /// Edges without triggers, which are considered for every event.
+    untriggered: Vec<EdgeId>,
+    /// Eventless edges, which are never considered for an event.
+    eventless: Vec<EdgeId>,
+}
+
+impl TriggerIndex {
+    /// The edges to consider for `event_id`, in the order they were defined.
+    fn candidates(&self, event_id: &str) -> Vec<EdgeId> {
+        let mut candidates = self
+            .by_event_id
+            .get(event_id)
+            .into_iter()
+            .flatten()
+            .chain(&self.untriggered)
+            .copied()
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        candidates
+    }
+}
+
+/// How many eventless edges a single dispatch takes before failing with
+/// `DispatchError::EventlessLoop`, unless the machine is configured otherwise.
+pub const DEFAULT_EVENTLESS_STEP_LIMIT: usize = 100;
+
+pub struct StateMachine<'a, EventPayload, EdgeInfo, Context> {
+    pub transition_history: Box<dyn HistoryStore<EventPayload, Context> + 'a>,
+    /// The active leaf states, one per active region.
+    active_states: BTreeSet<StateId>,
+    /// The leaf states that were active below each compound state when it was last exited.
+    last_active: HashMap<StateId, Vec<StateId>>,
+    pub current_context: Context,
+    initial_state: StateId,
+    states: Vec<State>,
+    edges: Vec<Edge<EdgeInfo>>,
+    state_to_edge_map: HashMap<StateId, Vec<EdgeId>>,
+    trigger_map: HashMap<StateId, TriggerIndex>,
+    hierarchy: Hierarchy,
+    event_handler: Option<SharedHandler<'a, EventPayload, EdgeInfo, Context>>,
+    transitions: HashMap<EdgeId, EdgeTransition<'a, EventPayload, Context>>,
+    conflict_policy: ConflictPolicy<'a, EdgeInfo>,
+    eventless_step_limit: usize,
+    start_dispatch_hook: Option<SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>>,
+    end_dispatch_hook: Option<SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>>,
+    on_state_entry_hook: Option<SharedHook<StateHook<'a, EventPayload, EdgeInfo, Context>>>,
+    on_state_exit_hook: Option<SharedHook<StateHook<'a, EventPayload, EdgeInfo, Context>>>,
+    on_edge_traversal_hook:
+        Option<SharedHook<EdgeTraversalHook<'a, EventPayload, EdgeInfo, Context>>>,
+    state_entry_hooks: HookMap<StateId, StateHook<'a, EventPayload, EdgeInfo, Context>>,
+    state_exit_hooks: HookMap<StateId, StateHook<'a, EventPayload, EdgeInfo, Context>>,
+    edge_traversal_hooks: HookMap<EdgeId, EdgeTraversalHook<'a, EventPayload, EdgeInfo, Context>>,
+    completion_hook: Option<SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>>,
+    event_queue: EventQueue<EventPayload>,
+    /// Undone transitions, the latest one last.
+    redo_stack: Vec<UndoneTransition<EventPayload, Context>>,
+    /// Whether the running dispatch has taken a transition yet.
+    dispatch_transitioned: bool,
+}
+
+impl<'a, EventPayload, EdgeInfo, Context> Clone
+    for StateMachine<'a, EventPayload, EdgeInfo, Context>
+where
+    EventPayload: 'a,
+    EdgeInfo: Clone,
+    Context: Clone + 'a,
+{
+    fn clone(&self) -> Self {
+        StateMachine {
+            transition_history: self.transition_history.clone_store(),
+            active_states: self.active_states.clone(),
+            last_active: self.last_active.clone(),
+            current_context: self.current_context.clone(),
+            initial_state: self.initial_state,
+            states: self.states.clone(),
+            edges: self.edges.clone(),
+            state_to_edge_map: self.state_to_edge_map.clone(),
+            trigger_map: self.trigger_map.clone(),
+            hierarchy: self.hierarchy.clone(),
+            event_handler: self.event_handler.clone(),
+            transitions: self.transitions.clone(),
+            conflict_policy: self.conflict_policy.clone(),
+            eventless_step_limit: self.eventless_step_limit,
+            start_dispatch_hook: self.start_dispatch_hook.clone(),
+            end_dispatch_hook: self.end_dispatch_hook.clone(),
+            on_state_entry_hook: self.on_state_entry_hook.clone(),
+            on_state_exit_hook: self.on_state_exit_hook.clone(),
+            on_edge_traversal_hook: self.on_edge_traversal_hook.clone(),
+            state_entry_hooks: self.state_entry_hooks.clone(),
+            state_exit_hooks: self.state_exit_hooks.clone(),
+            edge_traversal_hooks: self.edge_traversal_hooks.clone(),
+            completion_hook: self.completion_hook.clone(),
+            event_queue: self.event_queue.copy(),
+            redo_stack: self.redo_stack.clone(),
+            dispatch_transitioned: false,
+        }
+    }
+}
+
+impl<'a, EventPayload, EdgeInfo, Context> Debug
+    for StateMachine<'a, EventPayload, EdgeInfo, Context>
+where
+    EventPayload: Debug,
+    EdgeInfo: Debug,
+    Context: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateMachine")
+            .field("active_states", &self.active_states)
+            .field("transition_history", &self.transition_history)
+            .finish()
+    }
+}
+
+impl<'a, EventPayload, EdgeInfo, Context> StateMachine<'a, EventPayload, EdgeInfo, Context>
+where
+    EventPayload: Debug + Send + Sync + 'a,
+    EdgeInfo: Debug,
+    Context: Debug + Send + 'a,
+{
+    /// # Panics
+    ///
+    /// If the parents and initial children of `states` do not form a valid hierarchy, see
+    /// `MachineDefinition::validate`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        initial_state: StateId,
+        initial_context: Context,
+        states: Vec<State>,
+        edges: Vec<Edge<EdgeInfo>>,
+        event_handler: impl Handler<EventPayload, EdgeInfo, Context> + 'a,
+        start_dispatch_hook: Option<impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a>,
+        end_dispatch_hook: Option<impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a>,
+        on_state_entry_hook: Option<impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a>,
+        on_state_exit_hook: Option<impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a>,
+        on_edge_traversal_hook: Option<impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a>,
+    ) -> StateMachine<'a, EventPayload, EdgeInfo, Context> {
+        let hierarchy = Hierarchy::resolve(&states).unwrap_or_else(|error| panic!("{}", error));
+        StateMachine::from_parts(
+            initial_state,
+            initial_context,
+            states,
+            edges,
+            hierarchy,
+            Some(Arc::new(event_handler)),
+            start_dispatch_hook.map(|h| {
+                Arc::new(Mutex::new(h))
+                    as SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>
+            }),
+            end_dispatch_hook.map(|h| {
+                Arc::new(Mutex::new(h))
+                    as SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>
+            }),
+            on_state_entry_hook.map(|h| {
+                Arc::new(Mutex::new(h))
+                    as SharedHook<StateHook<'a, EventPayload, EdgeInfo, Context>>
+            }),
+            on_state_exit_hook.map(|h| {
+                Arc::new(Mutex::new(h))
+                    as SharedHook<StateHook<'a, EventPayload, EdgeInfo, Context>>
+            }),
+            on_edge_traversal_hook.map(|h| {
+                Arc::new(Mutex::new(h))
+                    as SharedHook<EdgeTraversalHook<'a, EventPayload, EdgeInfo, Context>>
+            }),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn from_parts(
+        initial_state: StateId,
+        initial_context: Context,
+        states: Vec<State>,
+        edges: Vec<Edge<EdgeInfo>>,
+        hierarchy: Hierarchy,
+        event_handler: Option<SharedHandler<'a, EventPayload, EdgeInfo, Context>>,
+        start_dispatch_hook: Option<SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>>,
+        end_dispatch_hook: Option<SharedHook<DispatchHook<'a, EventPayload, EdgeInfo, Context>>>,
+        on_state_entry_hook: Option<SharedHook<StateHook<'a, EventPayload, EdgeInfo, Context>>>,
+        on_state_exit_hook: Option<SharedHook<StateHook<'a, EventPayload, EdgeInfo, Context>>>,
+        on_edge_traversal_hook: Option<
+            SharedHook<EdgeTraversalHook<'a, EventPayload, EdgeInfo, Context>>,
+        >,
+    ) -> StateMachine<'a, EventPayload, EdgeInfo, Context> {
+        let mut state_to_edge_map = HashMap::new();
+        for state in (0..states.len()).map(StateId) {
+            let mut state_edges = Vec::new();
+            for (index, edge) in edges.iter().enumerate() {
+                if edge.from_state == state {
+                    state_edges.push(EdgeId(index));
                 }
             }
-            state_to_edge_map.insert(state, state_edges);
+            state_to_edge_map.insert(state, state_edges);
+        }
+        let mut trigger_map = (0..states.len())
+            .map(|state| (StateId(state), TriggerIndex::default()))
+            .collect::<HashMap<_, _>>();
+        for (index, edge) in edges.iter().enumerate() {
+            let trigger_index = trigger_map.entry(edge.from_state).or_default();
+            if edge.eventless {
+                trigger_index.eventless.push(EdgeId(index));
+                continue;
+            }
+            if edge.triggers.is_empty() {
+                trigger_index.untriggered.push(EdgeId(index));
+            }
+            for event_id in edge.triggers.iter().collect::<HashSet<_>>() {
+                trigger_index
+                    .by_event_id
+                    .entry(event_id.clone())
+                    .or_default()
+                    .push(EdgeId(index));
+            }
+        }
+        let mut state_machine = StateMachine {
+            transition_history: Box::new(UnboundedHistory::new()),
+            active_states: BTreeSet::new(),
+            last_active: HashMap::new(),
+            current_context: initial_context,
+            initial_state,
+            states,
+            edges,
+            state_to_edge_map,
+            trigger_map,
+            hierarchy,
+            event_handler,
+            transitions: HashMap::new(),
+            conflict_policy: ConflictPolicy::Reject,
+            eventless_step_limit: DEFAULT_EVENTLESS_STEP_LIMIT,
+            start_dispatch_hook,
+            end_dispatch_hook,
+            on_state_entry_hook,
+            on_state_exit_hook,
+            on_edge_traversal_hook,
+            state_entry_hooks: HashMap::new(),
+            state_exit_hooks: HashMap::new(),
+            edge_traversal_hooks: HashMap::new(),
+            completion_hook: None,
+            event_queue: EventQueue::new(),
+            redo_stack: Vec::new(),
+            dispatch_transitioned: false,
+        };
+        // An unknown initial state is reported by `validate` and `try_dispatch`.
+        state_machine.set_current_state(Some(initial_state));
+        state_machine
+    }
+
+    /// Builds a machine without hooks from `definition`, resolving its string ids into
+    /// `StateId`s and `EdgeId`s.
+    pub fn from_definition(
+        definition: MachineDefinition<EdgeInfo>,
+        initial_context: Context,
+        event_handler: impl Handler<EventPayload, EdgeInfo, Context> + 'a,
+    ) -> Result<StateMachine<'a, EventPayload, EdgeInfo, Context>, DefinitionError> {
+        let (initial_state, states, edges, hierarchy) = definition.hydrate()?;
+        Ok(StateMachine::from_parts(
+            initial_state,
+            initial_context,
+            states,
+            edges,
+            hierarchy,
+            Some(Arc::new(event_handler)),
+            None,
+            None,
+            None,
+            None,
+            None,
+        ))
+    }
+
+    pub fn definition(&self) -> MachineDefinition<EdgeInfo>
+    where
+        EdgeInfo: Clone,
+    {
+        MachineDefinition {
+            initial_state_id: self.states[self.initial_state.0].id.clone(),
+            states: self.states.clone(),
+            edges: self
+                .edges
+                .iter()
+                .map(|edge| edge.clone().dehydrate(&self.states))
+                .collect(),
+        }
+    }
+
+    /// Adds an entry hook that only runs when the state with id `state_id` is entered,
+    /// after the machine-wide `on_state_entry_hook`.
+    pub fn add_state_entry_hook(
+        &mut self,
+        state_id: &str,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Result<&mut Self, HydrationError> {
+        let state = find_state(&self.states, state_id, "state_id")?;
+        self.state_entry_hooks
+            .entry(state)
+            .or_default()
+            .push(Arc::new(Mutex::new(hook)));
+        Ok(self)
+    }
+
+    /// Adds an exit hook that only runs when the state with id `state_id` is exited,
+    /// after the machine-wide `on_state_exit_hook`.
+    pub fn add_state_exit_hook(
+        &mut self,
+        state_id: &str,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Result<&mut Self, HydrationError> {
+        let state = find_state(&self.states, state_id, "state_id")?;
+        self.state_exit_hooks
+            .entry(state)
+            .or_default()
+            .push(Arc::new(Mutex::new(hook)));
+        Ok(self)
+    }
+
+    /// Adds a traversal hook that only runs when the edge with id `edge_id` is traversed,
+    /// after the machine-wide `on_edge_traversal_hook`.
+    pub fn add_edge_traversal_hook(
+        &mut self,
+        edge_id: &str,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c Edge<EdgeInfo>,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> Result<&mut Self, HydrationError> {
+        let edge = self.find_edge(edge_id)?;
+        self.edge_traversal_hooks
+            .entry(edge)
+            .or_default()
+            .push(Arc::new(Mutex::new(hook)));
+        Ok(self)
+    }
+
+    /// Sets the hook called with the final state once a dispatch finishes the machine.
+    pub fn set_completion_hook(
+        &mut self,
+        hook: impl for<'c> FnMut(
+            &'c Event<EventPayload>,
+            &'c State,
+            &'c Context,
+            &'c Vec<State>,
+            &'c Vec<Edge<EdgeInfo>>
+        ) + Send + 'a,
+    ) -> &mut Self {
+        self.completion_hook = Some(Arc::new(Mutex::new(hook)));
+        self
+    }
+
+    /// Sets the guard deciding whether the edge with id `edge_id` accepts an event.
+    ///
+    /// Once an edge has a guard or an action the handler is no longer asked about it. An
+    /// edge with a guard but no action keeps the current context when it is taken.
+    pub fn set_guard(
+        &mut self,
+        edge_id: &str,
+        guard: impl Fn(&Event<EventPayload>, &Context) -> bool + Send + Sync + 'a,
+    ) -> Result<&mut Self, HydrationError>
+    where
+        Context: Clone,
+    {
+        let edge = self.find_edge(edge_id)?;
+        self.insert_guard(edge, Arc::new(guard), Context::clone);
+        Ok(self)
+    }
+
+    /// Sets the action building the new context when the edge with id `edge_id` is taken.
+    ///
+    /// The action only runs for the edge that is chosen, so it is free to do expensive work.
+    /// An edge with an action but no guard accepts every event.
+    pub fn set_action(
+        &mut self,
+        edge_id: &str,
+        action: impl Fn(&Event<EventPayload>, &Context) -> Context + Send + Sync + 'a,
+    ) -> Result<&mut Self, HydrationError> {
+        let edge = self.find_edge(edge_id)?;
+        self.insert_action(edge, Arc::new(action));
+        Ok(self)
+    }
+
+    pub(crate) fn find_edge(&self, edge_id: &str) -> Result<EdgeId, HydrationError> {
+        self.edge_id(edge_id)
+            .ok_or_else(|| HydrationError::MissingEdge {
+                id: edge_id.to_string(),
+                field: "edge_id",
+            })
+    }
+
+    pub(crate) fn insert_guard(
+        &mut self,
+        edge: EdgeId,
+        guard: Arc<Guard<'a, EventPayload, Context>>,
+        keep_context: fn(&Context) -> Context,
+    ) {
+        self.transitions
+            .entry(edge)
+            .or_insert_with(|| EdgeTransition {
+                guard: None,
+                action: TransitionAction::KeepContext(keep_context),
+            })
+            .guard = Some(guard);
+    }
+
+    pub(crate) fn insert_action(
+        &mut self,
+        edge: EdgeId,
+        action: Arc<Action<'a, EventPayload, Context>>,
+    ) {
+        let action = TransitionAction::Run(action);
+        match self.transitions.get_mut(&edge) {
+            Some(transition) => transition.action = action,
+            None => {
+                self.transitions.insert(edge, EdgeTransition { guard: None, action });
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> MachineSnapshot<EventPayload, Context>
+    where
+        Context: Clone,
+    {
+        MachineSnapshot {
+            current_state_id: self
+                .current_state()
+                .map(|state| self.states[state.0].id.clone()),
+            active_state_ids: self
+                .active_states
+                .iter()
+                .map(|state| self.states[state.0].id.clone())
+                .collect(),
+            last_active_state_ids: self
+                .last_active
+                .iter()
+                .map(|(state, leaves)| {
+                    let leaves = leaves.iter().map(|leaf| self.states[leaf.0].id.clone());
+                    (self.states[state.0].id.clone(), leaves.collect())
+                })
+                .collect(),
+            current_context: self.current_context.clone(),
+            transition_history: self
+                .transition_history
+                .iter()
+                .map(|record| record.clone().dehydrate(&self.states, &self.edges))
+                .collect(),
+        }
+    }
+
+    /// Rebuilds a machine from `definition` and resumes it where `snapshot` left off.
+    ///
+    /// Records that kept only the id of their event fail with
+    /// `HydrationError::MissingEvent`; hydrate those with `TransitionRecord::try_hydrate`.
+    pub fn restore(
+        definition: MachineDefinition<EdgeInfo>,
+        snapshot: MachineSnapshot<EventPayload, Context>,
+        event_handler: impl Handler<EventPayload, EdgeInfo, Context> + 'a,
+    ) -> Result<StateMachine<'a, EventPayload, EdgeInfo, Context>, DefinitionError> {
+        let MachineSnapshot {
+            current_state_id,
+            active_state_ids,
+            last_active_state_ids,
+            current_context,
+            transition_history,
+        } = snapshot;
+        let mut state_machine =
+            StateMachine::from_definition(definition, current_context, event_handler)?;
+        let current_state = current_state_id
+            .map(|id| find_state(&state_machine.states, &id, "current_state_id"))
+            .transpose()?;
+        state_machine.set_current_state(current_state);
+        if !active_state_ids.is_empty() {
+            state_machine.active_states = active_state_ids
+                .iter()
+                .map(|id| find_state(&state_machine.states, id, "active_state_ids"))
+                .collect::<Result<_, _>>()?;
+        }
+        for (id, leaf_ids) in last_active_state_ids {
+            let state = find_state(&state_machine.states, &id, "last_active_state_ids")?;
+            let leaves = leaf_ids
+                .iter()
+                .map(|id| find_state(&state_machine.states, id, "last_active_state_ids"))
+                .collect::<Result<_, _>>()?;
+            state_machine.last_active.insert(state, leaves);
+        }
+        for record in transition_history {
+            let record = TransitionRecord::try_hydrate(
+                record,
+                &state_machine.states,
+                &state_machine.edges,
+                &[],
+            )?;
+            state_machine.transition_history.push(record);
+        }
+        Ok(state_machine)
+    }
+
+    /// Takes back every transition from `transition_history[index]` on, restoring the
+    /// states and context the machine had before it. No hooks are called.
+    ///
+    /// The transitions taken back can be taken again with `redo`, until the next dispatch
+    /// that does not fail before taking a transition.
+    /// Indices count the records the history store kept, so a bounded store can only rewind
+    /// as far as its oldest record.
+    pub fn rewind_to(&mut self, index: usize) -> Result<(), RewindError> {
+        let len = self.transition_history.len();
+        if index >= len {
+            return Err(RewindError::OutOfRange { index, len });
+        }
+        if let Some(index) =
+            (index..len).find(|index| self.transition_history[*index].context.is_none())
+        {
+            return Err(RewindError::MissingContext { index });
+        }
+        for _ in index..len {
+            let mut record = match self.transition_history.pop() {
+                Some(record) => record,
+                None => break,
+            };
+            let active_states = self.active_states.clone();
+            // Every active leaf below the record's target was entered by it.
+            let hierarchy = &self.hierarchy;
+            let mut previous = active_states.clone();
+            previous.retain(|leaf| !hierarchy.is_descendant(*leaf, record.to_state));
+            previous.extend(record.exited_leaves().iter().copied());
+            self.set_active_states(previous);
+            let context = match record.context.take() {
+                Some(context) => std::mem::replace(&mut self.current_context, context),
+                None => unreachable!("checked above"),
+            };
+            self.redo_stack.push(UndoneTransition {
+                record,
+                active_states,
+                context,
+            });
+        }
+        Ok(())
+    }
+
+    /// Takes back every transition of the latest dispatch, see `rewind_to`.
+    pub fn undo(&mut self) -> Result<(), RewindError> {
+        let history = &self.transition_history;
+        if history.is_empty() {
+            return Err(RewindError::NothingToUndo);
+        }
+        let start = (0..history.len())
+            .rev()
+            .find(|index| !history[*index].continues_dispatch)
+            .unwrap_or(0);
+        self.rewind_to(start)
+    }
+
+    /// Takes the transitions of the latest dispatch taken back by `undo` or `rewind_to`
+    /// again. After `rewind_to` stopped within a dispatch, the rest of it is taken again.
+    pub fn redo(&mut self) -> Result<(), RewindError> {
+        if self.redo_stack.is_empty() {
+            return Err(RewindError::NothingToRedo);
+        }
+        while let Some(UndoneTransition {
+            mut record,
+            active_states,
+            context,
+        }) = self.redo_stack.pop()
+        {
+            self.set_active_states(active_states);
+            record.context = Some(std::mem::replace(&mut self.current_context, context));
+            self.transition_history.push(record);
+            match self.redo_stack.last() {
+                Some(undone) if undone.record.continues_dispatch => {}
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `redo` has a transition to take again.
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    pub fn conflict_policy(&self) -> &ConflictPolicy<'a, EdgeInfo> {
+        &self.conflict_policy
+    }
+
+    pub fn set_conflict_policy(&mut self, conflict_policy: ConflictPolicy<'a, EdgeInfo>) {
+        self.conflict_policy = conflict_policy;
+    }
+
+    /// A handle to the queue of events raised for this machine. A clone of the machine
+    /// starts with a queue of its own; while either of them dispatches, the hooks they share
+    /// raise events on the queue of the one dispatching.
+    pub fn event_queue(&self) -> EventQueue<EventPayload> {
+        self.event_queue.clone()
+    }
+
+    /// Dispatches the events raised on `event_queue` instead of the machine's own queue, so
+    /// hooks registered before the machine was built can raise events.
+    pub fn set_event_queue(&mut self, event_queue: EventQueue<EventPayload>) {
+        self.event_queue = event_queue;
+    }
+
+    /// Keeps the records of later transitions in `history_store`, dropping the records kept
+    /// so far.
+    pub fn set_history_store(
+        &mut self,
+        history_store: impl HistoryStore<EventPayload, Context> + 'a,
+    ) {
+        self.transition_history = Box::new(history_store);
+    }
+
+    pub fn eventless_step_limit(&self) -> usize {
+        self.eventless_step_limit
+    }
+
+    /// Sets how many eventless edges a single dispatch may take, see `try_dispatch`.
+    pub fn set_eventless_step_limit(&mut self, eventless_step_limit: usize) {
+        self.eventless_step_limit = eventless_step_limit;
+    }
+
+    pub fn states(&self) -> &[State] {
+        &self.states
+    }
+
+    pub fn edges(&self) -> &[Edge<EdgeInfo>] {
+        &self.edges
+    }
+
+    pub fn state(&self, state: StateId) -> Option<&State> {
+        self.states.get(state.0)
+    }
+
+    pub fn edge(&self, edge: EdgeId) -> Option<&Edge<EdgeInfo>> {
+        self.edges.get(edge.0)
+    }
+
+    /// The compound state `state` is nested in.
+    pub fn parent(&self, state: StateId) -> Option<StateId> {
+        self.hierarchy.parent(state)
+    }
+
+    /// The innermost state containing every active state. Without parallel regions this is
+    /// the single active leaf state.
+    pub fn current_state(&self) -> Option<StateId> {
+        self.hierarchy
+            .common_ancestor(self.active_states.iter().copied())
+    }
+
+    /// Makes `state` the current state, as if it had been entered on its own, or stops the
+    /// machine if `state` is `None`. No hooks are called.
+    pub fn set_current_state(&mut self, state: Option<StateId>) {
+        let active_states = match state {
+            Some(state) if state.0 < self.states.len() => {
+                self.hierarchy.initial_leaves(state).into_iter().collect()
+            }
+            state => state.into_iter().collect(),
+        };
+        self.set_active_states(active_states);
+    }
+
+    /// Replaces the active leaf states, remembering the leaves that were active below every
+    /// compound state that stops being active.
+    fn set_active_states(&mut self, active_states: BTreeSet<StateId>) {
+        let hierarchy = &self.hierarchy;
+        let mut exited = HashSet::new();
+        for leaf in &self.active_states {
+            for state in hierarchy.ancestors(*leaf).skip(1) {
+                let within = |leaf: &StateId| hierarchy.is_descendant(*leaf, state);
+                if active_states.iter().any(within) || !exited.insert(state) {
+                    continue;
+                }
+                let leaves = self.active_states.iter().copied().filter(within).collect();
+                self.last_active.insert(state, leaves);
+            }
+        }
+        self.active_states = active_states;
+    }
+
+    /// The active leaf states, one for every active region.
+    pub fn active_states(&self) -> &BTreeSet<StateId> {
+        &self.active_states
+    }
+
+    /// Every active state: the active leaf states and all of their ancestors.
+    pub fn configuration(&self) -> BTreeSet<StateId> {
+        self.active_states
+            .iter()
+            .flat_map(|state| self.hierarchy.ancestors(*state))
+            .collect()
+    }
+
+    /// The active final state without a parent, if the machine has reached one.
+    pub fn final_state(&self) -> Option<StateId> {
+        self.active_states.iter().copied().find(|state| {
+            self.states
+                .get(state.0)
+                .is_some_and(|state| state.final_state && state.parent.is_none())
+        })
+    }
+
+    /// Whether the machine has reached a final state without a parent. A finished machine
+    /// rejects every event with `DispatchError::Finished`.
+    pub fn is_finished(&self) -> bool {
+        self.final_state().is_some()
+    }
+
+    /// Whether `state` is active, either as a leaf or as the ancestor of one.
+    pub fn is_in(&self, state: StateId) -> bool {
+        self.active_states
+            .iter()
+            .any(|active_state| self.hierarchy.is_descendant(*active_state, state))
+    }
+
+    pub fn state_id(&self, id: &str) -> Option<StateId> {
+        self.states.iter().position(|state| state.id == id).map(StateId)
+    }
+
+    pub fn edge_id(&self, id: &str) -> Option<EdgeId> {
+        self.edges.iter().position(|edge| edge.id == id).map(EdgeId)
+    }
+
+    /// Dispatches `event`, panicking if the dispatch fails. See `try_dispatch`.
+    pub fn dispatch(&mut self, event: impl Into<Arc<Event<EventPayload>>>) {
+        if let Err(error) = self.try_dispatch(event) {
+            panic!("{}", error);
+        }
+    }
+
+    /// Dispatches `event` to the edges leaving the current state that are triggered by its
+    /// id or have no triggers.
+    ///
+    /// `event` is taken by value or as an `Arc`, and shared with `transition_history` by
+    /// every transition it causes.
+    ///
+    /// Events no edge of a state accepts bubble up to the edges of its ancestors. With
+    /// parallel regions every active region chooses an edge for the event before any of them
+    /// is taken, and the edges are then taken one after the other. Of two edges that would
+    /// exit the same state only one is taken: the one leaving the inner state, or else the
+    /// one chosen first.
+    ///
+    /// When an edge is taken, the state exit hook, the edge traversal hook and the state
+    /// entry hook run in that order. Once a dispatch finishes the machine the completion hook
+    /// runs after the end dispatch hook, and every later dispatch fails.
+    ///
+    /// After a transition, eventless edges are taken with the same event until none of them
+    /// accepts it, each hop recorded in `transition_history`. Taking more than
+    /// `eventless_step_limit` of them fails with `DispatchError::EventlessLoop`.
+    ///
+    /// Events raised on the `event_queue` are then dispatched in order, each once the one
+    /// before it has settled, until the queue is empty or the machine finishes. The start and
+    /// end dispatch hooks run once around the whole run, with `event`.
+    ///
+    /// An error before any edge is taken leaves the machine unchanged, and the end dispatch
+    /// hook is not called. Edges taken before an error are kept: the end dispatch hook still
+    /// runs, the rest of the queue is dropped, and the error is returned as
+    /// `DispatchError::PartlyApplied` with the outcome so far.
+    pub fn try_dispatch(
+        &mut self,
+        event: impl Into<Arc<Event<EventPayload>>>,
+    ) -> Result<DispatchOutcome, DispatchError> {
+        let event = &event.into();
+        let previous_state = self.current_state().ok_or(DispatchError::NoCurrentState)?;
+        if let Some(state) = self
+            .active_states
+            .iter()
+            .find(|state| state.0 >= self.states.len())
+        {
+            return Err(DispatchError::UnknownState { state: *state });
+        }
+        if let Some(final_state) = self.final_state() {
+            return Err(DispatchError::Finished {
+                state_id: self.states[final_state.0].id.clone(),
+            });
+        }
+
+        // Hooks shared with clones of the machine raise their events for this one.
+        let _dispatching = self.event_queue.dispatching();
+        if let Some(start_dispatch_hook) = &self.start_dispatch_hook {
+            lock(start_dispatch_hook)(
+                event,
+                &self.states[previous_state.0],
+                &self.current_context,
+                &self.states,
+                &self.edges
+            );
+        }
+
+        self.dispatch_transitioned = false;
+        let mut outcome = DispatchOutcome {
+            previous_state,
+            new_state: previous_state,
+            edge: None,
+            resolved_by: None,
+        };
+        let result = self.step(event, &mut outcome).and_then(|()| self.step_raised(&mut outcome));
+        if let Err(error) = &result {
+            self.event_queue.clear();
+            if !outcome.transitioned() {
+                return Err(error.clone());
+            }
+        }
+        self.redo_stack.clear();
+
+        let new_state = self.current_state().ok_or(DispatchError::NoCurrentState)?;
+        outcome.new_state = new_state;
+        if let Some(end_dispatch_hook) = &self.end_dispatch_hook {
+            lock(end_dispatch_hook)(
+                event,
+                &self.states[new_state.0],
+                &self.current_context,
+                &self.states,
+                &self.edges
+            );
+        }
+        if let (Some(final_state), Some(completion_hook)) =
+            (self.final_state(), &self.completion_hook)
+        {
+            lock(completion_hook)(
+                event,
+                &self.states[final_state.0],
+                &self.current_context,
+                &self.states,
+                &self.edges,
+            );
+        }
+
+        match result {
+            Ok(()) => Ok(outcome),
+            Err(error) => Err(DispatchError::PartlyApplied {
+                outcome,
+                error: Box::new(error),
+            }),
+        }
+    }
+
+    /// Takes the edges chosen for `event` and then the eventless edges. Unless `outcome`
+    /// already has an edge, the first edge taken for `event` and the conflict policy that
+    /// chose it become its edge.
+    fn step(
+        &mut self,
+        event: &Arc<Event<EventPayload>>,
+        outcome: &mut DispatchOutcome,
+    ) -> Result<(), DispatchError> {
+        let selected = self.select_edges(event, false)?;
+        let (edge, resolved_by) = self.take_edges(event, selected);
+        if edge.is_some() {
+            if !outcome.transitioned() {
+                outcome.edge = edge;
+                outcome.resolved_by = resolved_by;
+            }
+            self.take_eventless_edges(event)?;
+        }
+        Ok(())
+    }
+
+    /// Steps through the events raised on the `event_queue` until it is empty or the
+    /// machine finishes.
+    fn step_raised(&mut self, outcome: &mut DispatchOutcome) -> Result<(), DispatchError> {
+        while let Some(raised) = self.event_queue.pop() {
+            if self.is_finished() {
+                self.event_queue.clear();
+                break;
+            }
+            self.step(&raised, outcome)?;
+        }
+        Ok(())
+    }
+
+    /// Chooses an edge for `event` in every active region, either among the edges triggered
+    /// by it or among the eventless edges.
+    fn select_edges(
+        &self,
+        event: &Event<EventPayload>,
+        eventless: bool,
+    ) -> Result<Vec<Selection<Context>>, DispatchError> {
+        let mut selected: Vec<(Selection<Context>, Vec<StateId>)> = Vec::new();
+        for active_state in &self.active_states {
+            let selection = match self.select_edge(event, *active_state, eventless)? {
+                Some(selection) => selection,
+                None => continue,
+            };
+            if selected.iter().any(|((edge, _, _), _)| *edge == selection.0) {
+                continue;
+            }
+            // Edges exiting the same state conflict. The one from the inner source wins, and
+            // otherwise the one selected first.
+            let source = self.edges[selection.0 .0].from_state;
+            let exit_set = self.exit_set(selection.0);
+            let mut preempted = false;
+            let mut overridden = Vec::new();
+            for (index, ((edge, _, _), other_exit_set)) in selected.iter().enumerate() {
+                if !exit_set.iter().any(|state| other_exit_set.contains(state)) {
+                    continue;
+                }
+                let other_source = self.edges[edge.0].from_state;
+                if source != other_source && self.hierarchy.is_descendant(source, other_source) {
+                    overridden.push(index);
+                } else {
+                    preempted = true;
+                    break;
+                }
+            }
+            if !preempted {
+                for index in overridden.into_iter().rev() {
+                    selected.remove(index);
+                }
+                selected.push((selection, exit_set));
+            }
+        }
+        Ok(selected.into_iter().map(|(selection, _)| selection).collect())
+    }
+
+    /// The active states taking `edge` would exit.
+    fn exit_set(&self, edge: EdgeId) -> Vec<StateId> {
+        let from_state = self.edges[edge.0].from_state;
+        let mut to_state = self.edges[edge.0].to_state;
+        if let (Some(_), Some(parent)) = (
+            self.hierarchy.history(to_state),
+            self.hierarchy.parent(to_state),
+        ) {
+            to_state = parent;
+        }
+        let domain = self.hierarchy.domain(from_state, to_state);
+        self.hierarchy.exit(domain, &self.active_states).0
+    }
+
+    /// Takes the `selected` edges one after the other, returning the first edge taken and the
+    /// conflict policy that chose it.
+    fn take_edges(
+        &mut self,
+        event: &Arc<Event<EventPayload>>,
+        selected: Vec<Selection<Context>>,
+    ) -> (Option<EdgeId>, Option<ConflictPolicyKind>) {
+        let mut edge = None;
+        let mut resolved_by = None;
+        for (selected_edge, new_context, resolution) in selected {
+            let new_context = match new_context {
+                Some(new_context) => new_context,
+                None => match &self.transitions[&selected_edge].action {
+                    TransitionAction::Run(action) => action(event, &self.current_context),
+                    TransitionAction::KeepContext(copy) => copy(&self.current_context),
+                },
+            };
+            self.transition(event, selected_edge, new_context);
+            edge = edge.or(Some(selected_edge));
+            resolved_by = resolved_by.or(resolution);
+        }
+        (edge, resolved_by)
+    }
+
+    /// Takes eventless edges until none of them accepts `event`, or the machine finishes.
+    fn take_eventless_edges(
+        &mut self,
+        event: &Arc<Event<EventPayload>>,
+    ) -> Result<(), DispatchError> {
+        let mut steps = 0;
+        while !self.is_finished() {
+            let selected = self.select_edges(event, true)?;
+            if selected.is_empty() {
+                break;
+            }
+            if steps == self.eventless_step_limit {
+                let state = self.current_state().ok_or(DispatchError::NoCurrentState)?;
+                return Err(DispatchError::EventlessLoop {
+                    state_id: self.states[state.0].id.clone(),
+                    limit: self.eventless_step_limit,
+                });
+            }
+            self.take_edges(event, selected);
+            steps += 1;
+        }
+        Ok(())
+    }
+
+    /// Chooses the edge `active_state` takes for `event`, along with the context the handler
+    /// built for it and the conflict policy that chose it.
+    ///
+    /// Edges with a guard or an action only evaluate the guard here, the context for them is
+    /// built once the edge is taken.
+    fn select_edge(
+        &self,
+        event: &Event<EventPayload>,
+        active_state: StateId,
+        eventless: bool,
+    ) -> Result<Option<Selection<Context>>, DispatchError> {
+        for state in self.hierarchy.ancestors(active_state) {
+            let trigger_index = &self.trigger_map[&state];
+            let candidates = if eventless {
+                trigger_index.eventless.clone()
+            } else {
+                trigger_index.candidates(&event.id)
+            };
+            let mut transitioning_edges = Vec::new();
+            for edge in candidates {
+                match (self.transitions.get(&edge), &self.event_handler) {
+                    (Some(transition), _) => {
+                        let accepted = transition
+                            .guard
+                            .as_ref()
+                            .is_none_or(|guard| guard(event, &self.current_context));
+                        if accepted {
+                            transitioning_edges.push((edge, None));
+                        }
+                    }
+                    (None, Some(event_handler)) => {
+                        if let Some(new_context) =
+                            event_handler.handle(event, &self.edges[edge.0], &self.current_context)
+                        {
+                            transitioning_edges.push((edge, Some(new_context)));
+                        }
+                    }
+                    (None, None) => {}
+                }
+            }
+            if transitioning_edges.len() > 1 {
+                let chosen = self.resolve_conflict(&transitioning_edges).map_err(|tied| {
+                    DispatchError::AmbiguousTransition {
+                        state_id: self.states[state.0].id.clone(),
+                        edge_ids: tied
+                            .into_iter()
+                            .map(|edge| self.edges[edge.0].id.clone())
+                            .collect(),
+                    }
+                })?;
+                let (edge, new_context) = transitioning_edges.swap_remove(chosen);
+                return Ok(Some((edge, new_context, Some(self.conflict_policy.kind()))));
+            }
+            if let Some((edge, new_context)) = transitioning_edges.pop() {
+                return Ok(Some((edge, new_context, None)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Applies the conflict policy to the edges that accepted an event, returning the index
+    /// of the one to take or the edges that could not be told apart.
+    fn resolve_conflict<T>(&self, candidates: &[(EdgeId, T)]) -> Result<usize, Vec<EdgeId>> {
+        let all = || candidates.iter().map(|(edge, _)| *edge).collect();
+        match &self.conflict_policy {
+            ConflictPolicy::Reject => Err(all()),
+            ConflictPolicy::FirstMatch => Ok(0),
+            ConflictPolicy::Priority => {
+                let priority = |(edge, _): &(EdgeId, T)| self.edges[edge.0].priority;
+                let highest = candidates.iter().map(priority).max();
+                let tied = (0..candidates.len())
+                    .filter(|index| Some(priority(&candidates[*index])) == highest)
+                    .collect::<Vec<_>>();
+                match tied.as_slice() {
+                    [chosen] => Ok(*chosen),
+                    _ => Err(tied.into_iter().map(|index| candidates[index].0).collect()),
+                }
+            }
+            ConflictPolicy::Custom(resolver) => {
+                let edges = candidates
+                    .iter()
+                    .map(|(edge, _)| &self.edges[edge.0])
+                    .collect::<Vec<_>>();
+                resolver(&edges)
+                    .filter(|chosen| *chosen < candidates.len())
+                    .ok_or_else(all)
+            }
+        }
+    }
+
+    /// The leaf states last active below `state`: the active ones if `state` is active, and
+    /// otherwise the ones active when it was last exited.
+    fn last_active_within(&self, state: StateId) -> Vec<StateId> {
+        if self.is_in(state) {
+            let within = |leaf: &StateId| self.hierarchy.is_descendant(*leaf, state);
+            return self.active_states.iter().copied().filter(within).collect();
+        }
+        self.last_active.get(&state).cloned().unwrap_or_default()
+    }
+
+    /// Takes `edge`, exiting every active state below the least common ancestor of the edge's
+    /// ends and entering every state down to the target's initial leaves.
+    fn transition(&mut self, event: &Arc<Event<EventPayload>>, edge: EdgeId, context: Context) {
+        // The undone transitions no longer follow on from the configuration.
+        self.redo_stack.clear();
+        let from_state = self.edges[edge.0].from_state;
+        let mut to_state = self.edges[edge.0].to_state;
+        // An edge into a history pseudo-state enters its parent, heading for the states that
+        // were last active there.
+        let mut descendants = Vec::new();
+        if let (Some(kind), Some(parent)) = (
+            self.hierarchy.history(to_state),
+            self.hierarchy.parent(to_state),
+        ) {
+            let last_active = self.last_active_within(parent);
+            descendants = self.hierarchy.history_targets(parent, kind, &last_active);
+            to_state = parent;
+        }
+        let domain = self.hierarchy.domain(from_state, to_state);
+        let (exited, exited_leaves) = self.hierarchy.exit(domain, &self.active_states);
+        let (entered, entered_leaves) = self.hierarchy.entry(domain, to_state, &descendants);
+
+        for state in exited {
+            let exit_hooks = self
+                .on_state_exit_hook
+                .iter()
+                .chain(self.state_exit_hooks.get(&state).into_iter().flatten());
+            for on_state_exit_hook in exit_hooks {
+                lock(on_state_exit_hook)(
+                    event,
+                    &self.states[state.0],
+                    &self.edges[edge.0],
+                    &self.current_context,
+                    &self.states,
+                    &self.edges,
+                );
+            }
+        }
+        let traversal_hooks = self
+            .on_edge_traversal_hook
+            .iter()
+            .chain(self.edge_traversal_hooks.get(&edge).into_iter().flatten());
+        for on_edge_traversal_hook in traversal_hooks {
+            lock(on_edge_traversal_hook)(
+                event,
+                &self.edges[edge.0],
+                &context,
+                &self.states,
+                &self.edges,
+            );
+        }
+        self.transition_history.push(TransitionRecord {
+            context: Some(std::mem::replace(&mut self.current_context, context)),
+            edge,
+            event: event.clone(),
+            from_state: self
+                .hierarchy
+                .common_ancestor(exited_leaves.iter().copied())
+                .unwrap_or(from_state),
+            to_state: self
+                .hierarchy
+                .common_ancestor(entered_leaves.iter().copied())
+                .unwrap_or(to_state),
+            exited_states: if exited_leaves.len() > 1 {
+                exited_leaves.clone()
+            } else {
+                Vec::new()
+            },
+            continues_dispatch: self.dispatch_transitioned,
+        });
+        self.dispatch_transitioned = true;
+        let mut active_states = self.active_states.clone();
+        for state in exited_leaves {
+            active_states.remove(&state);
+        }
+        active_states.extend(entered_leaves);
+        self.set_active_states(active_states);
+        for state in entered {
+            let entry_hooks = self
+                .on_state_entry_hook
+                .iter()
+                .chain(self.state_entry_hooks.get(&state).into_iter().flatten());
+            for on_state_entry_hook in entry_hooks {
+                lock(on_state_entry_hook)(
+                    event,
+                    &self.states[state.0],
+                    &self.edges[edge.0],
+                    &self.current_context,
+                    &self.states,
+                    &self.edges,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn it_appropriately_transitions() {
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            Some(())
+        }
+
+        let mut state_machine = StateMachineBuilder::new()
+            .state("first_state")
+            .state("second_state")
+            .edge("from first to second", "first_state", "second_state", "do it".to_string())
+            .initial("first_state")
+            .context(())
+            .handler(event_handler)
+            .build()
+            .unwrap();
+
+        let event1 = Event {
+            id: "first event".to_string(),
+            payload: (),
+        };
+
+        state_machine.dispatch(event1.clone());
+
+        assert_eq!(state_machine.current_state(), state_machine.state_id("second_state"));
+    }
+
+    #[test]
+    fn it_appropriately_does_not_transition() {
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            None
+        }
+
+        let mut state_machine = StateMachineBuilder::new()
+            .state("first_state")
+            .state("second_state")
+            .edge("from first to second", "first_state", "second_state", "do it".to_string())
+            .initial("first_state")
+            .context(())
+            .handler(event_handler)
+            .build()
+            .unwrap();
+
+        let event1 = Event {
+            id: "first event".to_string(),
+            payload: (),
+        };
+
+        state_machine.dispatch(event1.clone());
+
+        assert_eq!(state_machine.current_state(), state_machine.state_id("first_state"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot have multiple transitioning edges")]
+    fn it_panics_on_multiple_transitions() {
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            Some(())
+        }
+
+        let mut state_machine = StateMachineBuilder::new()
+            .state("first_state")
+            .state("second_state")
+            .edge("from first to second", "first_state", "second_state", "do it".to_string())
+            .edge(
+                "from first to second again",
+                "first_state",
+                "second_state",
+                "do it again".to_string(),
+            )
+            .initial("first_state")
+            .context(())
+            .handler(event_handler)
+            .build()
+            .unwrap();
+
+        let event1 = Event {
+            id: "first event".to_string(),
+            payload: (),
+        };
+
+        state_machine.dispatch(event1.clone());
+    }
+
+    pub(crate) type NoDispatchHook =
+        fn(&Event<()>, &State, &(), &Vec<State>, &Vec<Edge<String>>);
+    pub(crate) type NoStateHook =
+        fn(&Event<()>, &State, &Edge<String>, &(), &Vec<State>, &Vec<Edge<String>>);
+    pub(crate) type NoEdgeTraversalHook =
+        fn(&Event<()>, &Edge<String>, &(), &Vec<State>, &Vec<Edge<String>>);
+
+    /// A player whose shallow history pseudo-state `resume` restarts it where it stopped.
+    pub(crate) fn media_player() -> StateMachine<'static, (), (), ()> {
+        StateMachineBuilder::new()
+            .state("player")
+            .state("playing")
+            .state("paused")
+            .state("resume")
+            .state("off")
+            .parent("playing", "player")
+            .parent("paused", "player")
+            .parent("resume", "player")
+            .initial_child("player", "playing")
+            .history("resume", HistoryKind::Shallow)
+            .edge("pause", "playing", "paused", ())
+            .edge("play", "paused", "playing", ())
+            .edge("stop", "player", "off", ())
+            .edge("start", "off", "resume", ())
+            .initial("off")
+            .context(())
+            .handler(|_: &Event<()>, _: &Edge<()>, _: &()| None)
+            .build()
+            .unwrap()
+    }
+
+    /// Accepts an event for the edges whose info is the event's id.
+    fn accept_matching_info(event: &Event<()>, edge: &Edge<String>, _: &()) -> Option<()> {
+        if edge.info() == event.id() {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn try_dispatch_reports_the_transition() {
+        let state1 = State::new("first_state");
+        let state2 = State::new("second_state");
+        let edge1 = Edge::new(
+            "from first to second".to_string(),
+            StateId(0),
+            StateId(1),
+            "do it".to_string(),
+        );
+
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            Some(())
+        }
+
+        let mut state_machine = StateMachine::new(
+            StateId(0),
+            (),
+            vec![state1, state2],
+            vec![edge1],
+            event_handler as EventHandler<(), String, ()>,
+            None::<NoDispatchHook>,
+            None::<NoDispatchHook>,
+            None::<NoStateHook>,
+            None::<NoStateHook>,
+            None::<NoEdgeTraversalHook>,
+        );
+
+        let event1 = Event {
+            id: "first event".to_string(),
+            payload: (),
+        };
+
+        let outcome = state_machine.try_dispatch(event1.clone()).unwrap();
+        assert!(outcome.transitioned());
+        assert_eq!(outcome.previous_state, StateId(0));
+        assert_eq!(outcome.new_state, StateId(1));
+        assert_eq!(outcome.edge, state_machine.edge_id("from first to second"));
+        assert_eq!(state_machine.current_state(), Some(StateId(1)));
+        assert_eq!(state_machine.transition_history.len(), 1);
+
+        let outcome = state_machine.try_dispatch(event1.clone()).unwrap();
+        assert!(!outcome.transitioned());
+        assert_eq!(outcome.new_state, StateId(1));
+    }
+
+    #[test]
+    fn try_dispatch_rejects_multiple_transitioning_edges() {
+        let state1 = State::new("first_state");
+        let state2 = State::new("second_state");
+        let edge1 = Edge::new(
+            "from first to second".to_string(),
+            StateId(0),
+            StateId(1),
+            "do it".to_string(),
+        );
+        let edge2 = Edge::new(
+            "from first to second again".to_string(),
+            StateId(0),
+            StateId(1),
+            "do it again".to_string(),
+        );
+
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            Some(())
+        }
+
+        let mut state_machine = StateMachine::new(
+            StateId(0),
+            (),
+            vec![state1, state2],
+            vec![edge1, edge2],
+            event_handler as EventHandler<(), String, ()>,
+            None::<NoDispatchHook>,
+            None::<NoDispatchHook>,
+            None::<NoStateHook>,
+            None::<NoStateHook>,
+            None::<NoEdgeTraversalHook>,
+        );
+
+        let event1 = Event {
+            id: "first event".to_string(),
+            payload: (),
+        };
+
+        assert_eq!(
+            state_machine.try_dispatch(event1.clone()).unwrap_err(),
+            DispatchError::AmbiguousTransition {
+                state_id: "first_state".to_string(),
+                edge_ids: vec![
+                    "from first to second".to_string(),
+                    "from first to second again".to_string(),
+                ],
+            }
+        );
+        assert_eq!(state_machine.current_state(), Some(StateId(0)));
+        assert!(state_machine.transition_history.is_empty());
+
+        state_machine.set_current_state(None);
+        assert_eq!(
+            state_machine.try_dispatch(event1.clone()).unwrap_err(),
+            DispatchError::NoCurrentState
+        );
+    }
+
+    #[test]
+    fn try_hydrate_reports_missing_references() {
+        let states = vec![
+            State::new("first_state"),
+            State::new("second_state"),
+        ];
+
+        let deserializable_edge = DeserializableEdge::new(
+            "from first to third".to_string(),
+            "first_state".to_string(),
+            "third_state".to_string(),
+            (),
+        );
+        assert_eq!(
+            Edge::try_hydrate(deserializable_edge, &states).unwrap_err(),
+            HydrationError::MissingState {
+                id: "third_state".to_string(),
+                field: "to_state_id",
+            }
+        );
+
+        let edge1 = Edge::new(
+            "from first to second".to_string(),
+            StateId(0),
+            StateId(1),
+            (),
+        );
+        let event1: Event<()> = Event {
+            id: "first event".to_string(),
+            payload: (),
+        };
+        let event1 = Arc::new(event1);
+        let edges = vec![edge1];
+        let deserializable_transition_record = DeserializableTransitionRecord {
+            from_state_id: "first_state".to_string(),
+            to_state_id: "second_state".to_string(),
+            event: Some(event1.clone()),
+            event_id: None,
+            edge_id: "from second to first".to_string(),
+            context: Some(()),
+            exited_state_ids: Vec::new(),
+            continues_dispatch: false,
+        };
+        assert_eq!(
+            TransitionRecord::try_hydrate(deserializable_transition_record, &states, &edges, &[])
+                .unwrap_err(),
+            HydrationError::MissingEdge {
+                id: "from second to first".to_string(),
+                field: "edge_id",
+            }
+        );
+
+        // Records that only kept the id of their event look it up in the events given.
+        let legacy_record = |event_id: &str| {
+            let json = format!(
+                r#"{{
+                    "from_state_id": "first_state",
+                    "to_state_id": "second_state",
+                    "event_id": "{}",
+                    "edge_id": "from first to second",
+                    "context": null
+                }}"#,
+                event_id
+            );
+            serde_json::from_str::<DeserializableTransitionRecord<(), ()>>(&json).unwrap()
+        };
+        let events = std::slice::from_ref(&event1);
+        let record =
+            TransitionRecord::try_hydrate(legacy_record("first event"), &states, &edges, events)
+                .unwrap();
+        assert!(Arc::ptr_eq(record.event(), &event1));
+        assert_eq!(
+            TransitionRecord::try_hydrate(legacy_record("unknown event"), &states, &edges, events)
+                .unwrap_err(),
+            HydrationError::MissingEvent {
+                id: "unknown event".to_string(),
+                field: "event_id",
+            }
+        );
+    }
+
+    #[test]
+    fn it_round_trips_a_machine_definition() {
+        let json = r#"{
+            "initial_state_id": "first_state",
+            "states": [{ "id": "first_state" }, { "id": "second_state" }],
+            "edges": [{
+                "id": "from first to second",
+                "from_state_id": "first_state",
+                "to_state_id": "second_state",
+                "info": "do it"
+            }]
+        }"#;
+        let definition: MachineDefinition<String> = serde_json::from_str(json).unwrap();
+
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            Some(())
         }
-        StateMachine {
-            transition_history: Vec::new(),
-            current_state: Some(initial_state),
-            current_context: initial_context,
+
+        let mut state_machine = StateMachine::from_definition(
+            definition,
+            (),
+            event_handler as EventHandler<(), String, ()>,
+        )
+        .unwrap();
+
+        let event1 = Event {
+            id: "first event".to_string(),
+            payload: (),
+        };
+        state_machine.dispatch(event1.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("second_state"));
+
+        let saved = serde_json::to_value(state_machine.definition()).unwrap();
+        assert_eq!(saved, serde_json::from_str::<serde_json::Value>(json).unwrap());
+    }
+
+    #[test]
+    fn it_validates_a_machine_definition() {
+        let mut definition = MachineDefinition {
+            initial_state_id: "first_state".to_string(),
+            states: vec![
+                State::new("first_state"),
+                State::new("second_state"),
+            ],
+            edges: vec![DeserializableEdge::new(
+                "from first to third".to_string(),
+                "first_state".to_string(),
+                "third_state".to_string(),
+                (),
+            )],
+        };
+        assert_eq!(
+            definition.validate().unwrap_err(),
+            DefinitionError::Hydration(HydrationError::MissingState {
+                id: "third_state".to_string(),
+                field: "to_state_id",
+            })
+        );
+
+        definition.edges.clear();
+        definition.states.push(State::new("first_state"));
+        assert_eq!(
+            definition.validate().unwrap_err(),
+            DefinitionError::DuplicateState {
+                id: "first_state".to_string(),
+            }
+        );
+
+        definition.states.pop();
+        definition.initial_state_id = "third_state".to_string();
+        assert_eq!(
+            definition.validate().unwrap_err(),
+            DefinitionError::UnknownInitialState {
+                id: "third_state".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn it_can_be_returned_cloned_and_sent_across_threads() {
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            Some(())
+        }
+
+        fn build() -> StateMachine<'static, (), String, ()> {
+            let states = vec![State::new("first_state"), State::new("second_state")];
+            let edges = vec![Edge::new(
+                "from first to second",
+                StateId(0),
+                StateId(1),
+                "do it".to_string(),
+            )];
+            StateMachine::new(
+                StateId(0),
+                (),
+                states,
+                edges,
+                event_handler as EventHandler<(), String, ()>,
+                None::<NoDispatchHook>,
+                None::<NoDispatchHook>,
+                None::<NoStateHook>,
+                None::<NoStateHook>,
+                None::<NoEdgeTraversalHook>,
+            )
+        }
+
+        let state_machine = build();
+        let mut cloned = state_machine.clone();
+        let handle = std::thread::spawn(move || {
+            // Events are owned by the machine, so they can be made on the spot.
+            cloned.dispatch(Event::new("first event", ()));
+            cloned
+        });
+        let cloned = handle.join().unwrap();
+
+        assert_eq!(state_machine.current_state(), Some(StateId(0)));
+        assert_eq!(cloned.current_state(), Some(StateId(1)));
+        assert_eq!(cloned.transition_history.len(), 1);
+    }
+
+    #[test]
+    fn it_restores_a_snapshot() {
+        let definition = MachineDefinition {
+            initial_state_id: "first_state".to_string(),
+            states: vec![State::new("first_state"), State::new("second_state")],
+            edges: vec![
+                DeserializableEdge::new(
+                    "from first to second".to_string(),
+                    "first_state".to_string(),
+                    "second_state".to_string(),
+                    "do it".to_string(),
+                ),
+                DeserializableEdge::new(
+                    "from second to first".to_string(),
+                    "second_state".to_string(),
+                    "first_state".to_string(),
+                    "undo it".to_string(),
+                ),
+            ],
+        };
+
+        fn event_handler(event: &Event<u32>, _edge: &Edge<String>, context: &u32) -> Option<u32> {
+            Some(context + event.payload())
+        }
+
+        let mut state_machine = StateMachine::from_definition(
+            definition.clone(),
+            0,
+            event_handler as EventHandler<u32, String, u32>,
+        )
+        .unwrap();
+        state_machine.dispatch(Event::new("add", 1));
+        state_machine.dispatch(Event::new("add", 5));
+
+        let json = serde_json::to_string(&state_machine.snapshot()).unwrap();
+        let snapshot: MachineSnapshot<u32, u32> = serde_json::from_str(&json).unwrap();
+        let restored = StateMachine::restore(
+            definition.clone(),
+            snapshot,
+            event_handler as EventHandler<u32, String, u32>,
+        )
+        .unwrap();
+
+        // Snapshots written before records kept their event still load, but name only the
+        // event's id, which restoring cannot resolve.
+        let legacy_json = r#"{
+            "current_state_id": "second_state",
+            "current_context": 1,
+            "transition_history": [{
+                "from_state_id": "first_state",
+                "to_state_id": "second_state",
+                "event_id": "add",
+                "edge_id": "from first to second",
+                "context": 0
+            }]
+        }"#;
+        let legacy: MachineSnapshot<u32, u32> = serde_json::from_str(legacy_json).unwrap();
+        assert_eq!(
+            StateMachine::restore(
+                definition,
+                legacy,
+                event_handler as EventHandler<u32, String, u32>,
+            )
+            .unwrap_err(),
+            DefinitionError::Hydration(HydrationError::MissingEvent {
+                id: "add".to_string(),
+                field: "event_id",
+            })
+        );
+
+        assert_eq!(restored.current_state(), state_machine.current_state());
+        assert_eq!(restored.current_context, 6);
+        assert_eq!(restored.transition_history.len(), 2);
+        assert_eq!(restored.transition_history[1].context(), Some(&1));
+        assert_eq!(restored.transition_history[1].edge, EdgeId(1));
+        assert_eq!(
+            restored
+                .transition_history
+                .iter()
+                .map(|record| *record.event().payload())
+                .collect::<Vec<_>>(),
+            vec![1, 5]
+        );
+    }
+
+    #[test]
+    fn it_restores_contexts_that_serialize_as_null() {
+        let definition = MachineDefinition {
+            initial_state_id: "first_state".to_string(),
+            states: vec![State::new("first_state"), State::new("second_state")],
+            edges: vec![DeserializableEdge::new(
+                "from first to second".to_string(),
+                "first_state".to_string(),
+                "second_state".to_string(),
+                (),
+            )],
+        };
+
+        fn event_handler(_event: &Event<()>, _edge: &Edge<()>, _context: &()) -> Option<()> {
+            Some(())
+        }
+
+        let mut state_machine = StateMachine::from_definition(
+            definition.clone(),
+            (),
+            event_handler as EventHandler<(), (), ()>,
+        )
+        .unwrap();
+        state_machine.dispatch(Event::new("first event", ()));
+
+        let json = serde_json::to_string(&state_machine.snapshot()).unwrap();
+        let snapshot: MachineSnapshot<(), ()> = serde_json::from_str(&json).unwrap();
+        let mut restored =
+            StateMachine::restore(definition, snapshot, event_handler as EventHandler<(), (), ()>)
+                .unwrap();
+        assert_eq!(restored.transition_history[0].context(), Some(&()));
+        restored.undo().unwrap();
+        assert_eq!(restored.current_state(), Some(StateId(0)));
+    }
+
+    #[test]
+    fn it_calls_state_and_edge_hooks_in_order() {
+        let states = vec![State::new("first_state"), State::new("second_state")];
+        let edges = vec![Edge::new(
+            "from first to second",
+            StateId(0),
+            StateId(1),
+            "do it".to_string(),
+        )];
+
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            Some(())
+        }
+
+        let calls = Mutex::new(Vec::new());
+        let record = |name: &str, state: Option<&State>| {
+            let name = match state {
+                Some(state) => format!("{} {}", name, state.id()),
+                None => name.to_string(),
+            };
+            calls.lock().unwrap().push(name);
+        };
+
+        let mut state_machine: StateMachine<(), String, ()> = StateMachine::new(
+            StateId(0),
+            (),
             states,
             edges,
-            state_to_edge_map,
-            event_handler,
-            start_dispatch_hook: start_dispatch_hook.map(|h| Box::new(h) as Box<dyn for<'c> FnMut(
-                &'c Event<EventPayload>,
-                &'c State,
-                &'c Context,
-                &'c Vec<&'a State>,
-                &'c Vec<&'a Edge<'a, EdgeInfo>>
-            ) + 'a>),
-            end_dispatch_hook: end_dispatch_hook.map(|h| Box::new(h) as Box<dyn for<'c> FnMut(
-                &'c Event<EventPayload>,
-                &'c State,
-                &'c Context,
-                &'c Vec<&'a State>,
-                &'c Vec<&'a Edge<'a, EdgeInfo>>
-            ) + 'a>),
-            // on_state_entry_hook: on_state_entry_hook.map(|h| Box::new(h) as Box<dyn for<'c> FnMut(
-            //     &'c Event<EventPayload>,
-            //     &'c State,
-            //     &'c Edge<'a, EdgeInfo>,
-            //     &'c Context,
-            //     &'c Vec<&'a State>,
-            //     &'c Vec<&'a Edge<'a, EdgeInfo>>
-            // ) + 'a>),
-            // on_state_exit_hook: on_state_exit_hook.map(|h| Box::new(h) as Box<dyn for<'c> FnMut(
-            //     &'c Event<EventPayload>,
-            //     &'c State,
-            //     &'c Edge<'a, EdgeInfo>,
-            //     &'c Context,
-            //     &'c Vec<&'a State>,
-            //     &'c Vec<&'a Edge<'a, EdgeInfo>>
-            // ) + 'a>),
-            on_edge_traversal_hook: on_edge_traversal_hook.map(|h| Box::new(h) as Box<dyn for<'c> FnMut(
-                &'c Event<EventPayload>,
-                &'c Edge<'a, EdgeInfo>,
-                &'c Context,
-                &'c Vec<&'a State>,
-                &'c Vec<&'a Edge<'a, EdgeInfo>>
-            ) + 'a>),
-        }
-    }
-
-    pub fn dispatch(&mut self, event: &'b Event<EventPayload>) {
-        if let Some(start_dispatch_hook) = self.start_dispatch_hook.as_mut() {
-            start_dispatch_hook(
-                &event,
-                self.current_state.unwrap(),
-                &self.current_context,
-                &self.states,
-                &self.edges
-            );
+            event_handler as EventHandler<(), String, ()>,
+            Some(|_: &Event<()>, state: &State, _: &(), _: &Vec<State>, _: &Vec<Edge<String>>| {
+                record("start", Some(state))
+            }),
+            Some(|_: &Event<()>, state: &State, _: &(), _: &Vec<State>, _: &Vec<Edge<String>>| {
+                record("end", Some(state))
+            }),
+            Some(
+                |_: &Event<()>,
+                 state: &State,
+                 _: &Edge<String>,
+                 _: &(),
+                 _: &Vec<State>,
+                 _: &Vec<Edge<String>>| record("entry", Some(state)),
+            ),
+            Some(
+                |_: &Event<()>,
+                 state: &State,
+                 _: &Edge<String>,
+                 _: &(),
+                 _: &Vec<State>,
+                 _: &Vec<Edge<String>>| record("exit", Some(state)),
+            ),
+            Some(
+                |_: &Event<()>, _: &Edge<String>, _: &(), _: &Vec<State>, _: &Vec<Edge<String>>| {
+                    record("traverse", None)
+                },
+            ),
+        );
+
+        let event1 = Event::new("first event", ());
+        state_machine.dispatch(event1.clone());
+        std::mem::drop(state_machine);
+
+        assert_eq!(
+            calls.into_inner().unwrap(),
+            vec![
+                "start first_state",
+                "exit first_state",
+                "traverse",
+                "entry second_state",
+                "end second_state",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_calls_per_state_and_per_edge_hooks() {
+        let states = vec![
+            State::new("first_state"),
+            State::new("second_state"),
+            State::new("third_state"),
+        ];
+        let edges = vec![
+            Edge::new("from first to second", StateId(0), StateId(1), "do it".to_string()),
+            Edge::new("from second to third", StateId(1), StateId(2), "do it".to_string()),
+        ];
+
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            Some(())
         }
 
-        let current_state = self.current_state;
-        let current_context = &self.current_context;
-        let event_handler = self.event_handler;
-
-        let mut transitioning_edge = None;
-        let edges = self
-            .state_to_edge_map
-            .get(current_state.unwrap())
-            .expect("Could not find a state");
-        for edge in edges {
-            let event_handler_result = event_handler(event, edge, current_context);
-            if let Some(new_context) = event_handler_result {
-                assert!(
-                    transitioning_edge.is_none(),
-                    "Cannot have multiple transitioning edges"
-                );
-                transitioning_edge = Some((edge, new_context));
-            }
+        let calls = Mutex::new(Vec::new());
+        let mut state_machine: StateMachine<(), String, ()> = StateMachine::new(
+            StateId(0),
+            (),
+            states,
+            edges,
+            event_handler as EventHandler<(), String, ()>,
+            None::<NoDispatchHook>,
+            None::<NoDispatchHook>,
+            None::<NoStateHook>,
+            None::<NoStateHook>,
+            None::<NoEdgeTraversalHook>,
+        );
+        state_machine
+            .add_state_entry_hook(
+                "second_state",
+                |_: &Event<()>,
+                 state: &State,
+                 _: &Edge<String>,
+                 _: &(),
+                 _: &Vec<State>,
+                 _: &Vec<Edge<String>>| {
+                    calls.lock().unwrap().push(format!("entry {}", state.id()))
+                },
+            )
+            .unwrap()
+            .add_state_exit_hook(
+                "second_state",
+                |_: &Event<()>,
+                 state: &State,
+                 _: &Edge<String>,
+                 _: &(),
+                 _: &Vec<State>,
+                 _: &Vec<Edge<String>>| {
+                    calls.lock().unwrap().push(format!("exit {}", state.id()))
+                },
+            )
+            .unwrap()
+            .add_edge_traversal_hook(
+                "from second to third",
+                |_: &Event<()>,
+                 edge: &Edge<String>,
+                 _: &(),
+                 _: &Vec<State>,
+                 _: &Vec<Edge<String>>| {
+                    calls.lock().unwrap().push(format!("traverse {}", edge.id()))
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            state_machine
+                .add_edge_traversal_hook(
+                    "from third to first",
+                    |_: &Event<()>,
+                     _: &Edge<String>,
+                     _: &(),
+                     _: &Vec<State>,
+                     _: &Vec<Edge<String>>| {},
+                )
+                .err(),
+            Some(HydrationError::MissingEdge {
+                id: "from third to first".to_string(),
+                field: "edge_id",
+            })
+        );
+
+        let event1 = Event::new("first event", ());
+        state_machine.dispatch(event1.clone());
+        state_machine.dispatch(event1.clone());
+        std::mem::drop(state_machine);
+
+        assert_eq!(
+            calls.into_inner().unwrap(),
+            vec![
+                "entry second_state",
+                "exit second_state",
+                "traverse from second to third",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_accepts_closure_and_stateful_handlers() {
+        let threshold = 2;
+        let mut state_machine = StateMachineBuilder::new()
+            .state("first_state")
+            .state("second_state")
+            .edge("from first to second", "first_state", "second_state", ())
+            .initial("first_state")
+            .context(0)
+            .handler(move |_: &Event<()>, _: &Edge<()>, context: &u32| {
+                if *context >= threshold {
+                    None
+                } else {
+                    Some(context + 1)
+                }
+            })
+            .build()
+            .unwrap();
+        let event1 = Event::new("first event", ());
+        state_machine.dispatch(event1.clone());
+        assert_eq!(state_machine.current_context, 1);
+
+        struct CountingHandler {
+            calls: Arc<AtomicUsize>,
         }
-        if let Some((edge, new_context)) = transitioning_edge {
-            self.transition(event, edge, new_context);
+
+        impl Handler<(), (), ()> for CountingHandler {
+            fn handle(&self, _event: &Event<()>, _edge: &Edge<()>, _context: &()) -> Option<()> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                None
+            }
         }
 
-        if let Some(end_dispatch_hook) = self.end_dispatch_hook.as_mut() {
-            end_dispatch_hook(
-                &event,
-                self.current_state.unwrap(),
-                &self.current_context,
-                &self.states,
-                &self.edges
-            );
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut state_machine = StateMachineBuilder::new()
+            .state("first_state")
+            .state("second_state")
+            .edge("from first to second", "first_state", "second_state", ())
+            .initial("first_state")
+            .context(())
+            .handler(CountingHandler {
+                calls: calls.clone(),
+            })
+            .build()
+            .unwrap();
+        state_machine.dispatch(event1.clone());
+        state_machine.dispatch(event1.clone());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn it_resolves_conflicts_with_the_configured_policy() {
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            Some(())
         }
+
+        let build = |conflict_policy| {
+            StateMachineBuilder::new()
+                .state("first_state")
+                .state("second_state")
+                .state("third_state")
+                .edge("from first to second", "first_state", "second_state", "low".to_string())
+                .edge("from first to third", "first_state", "third_state", "high".to_string())
+                .priority("from first to third", 10)
+                .initial("first_state")
+                .context(())
+                .handler(event_handler)
+                .conflict_policy(conflict_policy)
+                .build()
+                .unwrap()
+        };
+        let event1 = Event::new("first event", ());
+
+        let mut state_machine = build(ConflictPolicy::FirstMatch);
+        let outcome = state_machine.try_dispatch(event1.clone()).unwrap();
+        assert_eq!(outcome.edge, state_machine.edge_id("from first to second"));
+        assert_eq!(outcome.resolved_by, Some(ConflictPolicyKind::FirstMatch));
+
+        let mut state_machine = build(ConflictPolicy::Priority);
+        let outcome = state_machine.try_dispatch(event1.clone()).unwrap();
+        assert_eq!(outcome.edge, state_machine.edge_id("from first to third"));
+        assert_eq!(outcome.resolved_by, Some(ConflictPolicyKind::Priority));
+
+        let mut state_machine = build(ConflictPolicy::Custom(Arc::new(|edges| {
+            edges.iter().position(|edge| edge.info() == "low")
+        })));
+        let outcome = state_machine.try_dispatch(event1.clone()).unwrap();
+        assert_eq!(outcome.edge, state_machine.edge_id("from first to second"));
+        assert_eq!(outcome.resolved_by, Some(ConflictPolicyKind::Custom));
+
+        let mut state_machine = build(ConflictPolicy::Reject);
+        assert!(matches!(
+            state_machine.try_dispatch(event1.clone()),
+            Err(DispatchError::AmbiguousTransition { .. })
+        ));
     }
 
-    fn transition(
-        &mut self,
-        event: &'b Event<EventPayload>,
-        edge: &'a Edge<EdgeInfo>,
-        context: Context,
-    ) {
-        // if let Some(on_state_exit_hook) = self.on_state_exit_hook.as_mut() {
-        //     on_state_exit_hook(
-        //         event,
-        //         self.current_state.unwrap(),
-        //         edge,
-        //         &self.current_context,
-        //         &self.states,
-        //         &self.edges,
-        //     );
-        // }
-        if let Some(on_edge_traversal_hook) = self.on_edge_traversal_hook.as_mut() {
-            on_edge_traversal_hook(
-                event,
-                edge,
-                &context,
-                &self.states,
-                &self.edges,
-            );
+    #[test]
+    fn it_runs_the_action_only_for_the_chosen_edge() {
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, context: &u32) -> Option<u32> {
+            Some(context + 100)
         }
-        self.transition_history.push(TransitionRecord {
-            context: std::mem::replace(&mut self.current_context, context),
-            edge,
-            event,
-            from_state: std::mem::replace(&mut self.current_state.unwrap(), edge.to_state),
-            to_state: edge.to_state,
-        });
-        // if let Some(on_state_entry_hook) = self.on_state_entry_hook.as_mut() {
-        //     on_state_entry_hook(
-        //         event,
-        //         self.current_state.unwrap(),
-        //         edge,
-        //         &self.current_context,
-        //         &self.states,
-        //         &self.edges,
-        //     );
-        // }
+
+        let guards = AtomicUsize::new(0);
+        let actions = AtomicUsize::new(0);
+        let mut state_machine = StateMachineBuilder::new()
+            .state("first_state")
+            .state("second_state")
+            .state("third_state")
+            .edge("from first to second", "first_state", "second_state", "guarded".to_string())
+            .edge("from first to third", "first_state", "third_state", "rejected".to_string())
+            .edge("from second to third", "second_state", "third_state", "handled".to_string())
+            .initial("first_state")
+            .context(0)
+            .handler(event_handler)
+            .guard("from first to second", |_, context: &u32| {
+                guards.fetch_add(1, Ordering::SeqCst);
+                *context == 0
+            })
+            .action("from first to second", |_, context: &u32| {
+                actions.fetch_add(1, Ordering::SeqCst);
+                context + 1
+            })
+            .guard("from first to third", |_, _: &u32| {
+                guards.fetch_add(1, Ordering::SeqCst);
+                false
+            })
+            .action("from first to third", |_, _: &u32| {
+                actions.fetch_add(1, Ordering::SeqCst);
+                unreachable!()
+            })
+            .build()
+            .unwrap();
+
+        let event1 = Event::new("first event", ());
+        let outcome = state_machine.try_dispatch(event1.clone()).unwrap();
+        assert_eq!(outcome.edge, state_machine.edge_id("from first to second"));
+        assert_eq!(state_machine.current_context, 1);
+        assert_eq!(guards.load(Ordering::SeqCst), 2);
+        assert_eq!(actions.load(Ordering::SeqCst), 1);
+
+        // Edges without a guard or an action still go through the handler.
+        state_machine.dispatch(event1.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("third_state"));
+        assert_eq!(state_machine.current_context, 101);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::*;
+    #[test]
+    fn it_only_considers_edges_triggered_by_the_event() {
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let handler_asked = asked.clone();
+        let event_handler = move |_: &Event<()>, edge: &Edge<String>, _: &()| {
+            handler_asked.lock().unwrap().push(edge.id().to_string());
+            Some(())
+        };
+        let mut state_machine = StateMachineBuilder::new()
+            .state("router")
+            .state("left")
+            .state("right")
+            .edge("to left", "router", "left", "left".to_string())
+            .edge("to right", "router", "right", "right".to_string())
+            .edge("back from left", "left", "router", "back".to_string())
+            .trigger("to left", "go left")
+            .trigger("to right", "go right")
+            .initial("router")
+            .context(())
+            .handler(event_handler)
+            .build()
+            .unwrap();
+
+        let go_right = Event::new("go right", ());
+        let go_left = Event::new("go left", ());
+        let stop = Event::new("stop", ());
+        assert!(!state_machine.try_dispatch(stop.clone()).unwrap().transitioned());
+        state_machine.dispatch(go_right.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("right"));
+        assert_eq!(*asked.lock().unwrap(), vec!["to right"]);
+
+        let left = state_machine.state_id("left");
+        state_machine.set_current_state(left);
+        state_machine.dispatch(stop.clone());
+        state_machine.dispatch(go_left.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("left"));
+        assert_eq!(
+            *asked.lock().unwrap(),
+            vec!["to right", "back from left", "to left"]
+        );
+    }
+
+    #[test]
+    fn it_bubbles_events_and_crosses_every_level_of_nested_states() {
+        let log = Mutex::new(Vec::new());
+        let mut state_machine = StateMachineBuilder::new()
+            .state("powered")
+            .state("idle")
+            .state("running")
+            .state("spinning")
+            .state("error")
+            .parent("idle", "powered")
+            .parent("running", "powered")
+            .parent("spinning", "running")
+            .initial_child("powered", "idle")
+            .initial_child("running", "spinning")
+            .edge("start", "idle", "running", "start".to_string())
+            .edge("fault", "powered", "error", "fault".to_string())
+            .edge("reset", "error", "powered", "reset".to_string())
+            .initial("powered")
+            .context(())
+            .handler(accept_matching_info)
+            .on_state_entry(
+                |_: &Event<()>,
+                 state: &State,
+                 _: &Edge<String>,
+                 _: &(),
+                 _: &Vec<State>,
+                 _: &Vec<Edge<String>>| {
+                    log.lock().unwrap().push(format!("enter {}", state.id()))
+                },
+            )
+            .on_state_exit(
+                |_: &Event<()>,
+                 state: &State,
+                 _: &Edge<String>,
+                 _: &(),
+                 _: &Vec<State>,
+                 _: &Vec<Edge<String>>| {
+                    log.lock().unwrap().push(format!("exit {}", state.id()))
+                },
+            )
+            .build()
+            .unwrap();
+        assert_eq!(state_machine.current_state(), state_machine.state_id("idle"));
+
+        let start = Event::new("start", ());
+        let fault = Event::new("fault", ());
+        let reset = Event::new("reset", ());
+        state_machine.dispatch(start.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("spinning"));
+        assert!(state_machine.is_in(state_machine.state_id("powered").unwrap()));
+        state_machine.dispatch(fault.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("error"));
+        state_machine.dispatch(reset.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("idle"));
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "exit idle",
+                "enter running",
+                "enter spinning",
+                "exit spinning",
+                "exit running",
+                "exit powered",
+                "enter error",
+                "exit error",
+                "enter powered",
+                "enter idle",
+            ]
+        );
 
-    // #[test]
-    // fn it_appropriately_transitions() {
-    //     let state1 = State {
-    //         id: "first_state".to_string(),
-    //     };
-    //     let state2 = State {
-    //         id: "second_state".to_string(),
-    //     };
-    //     let states = vec![&state1, &state2];
-    //
-    //     let edge1 = Edge {
-    //         id: "from first to second".to_string(),
-    //         from_state: &state1,
-    //         to_state: &state2,
-    //         info: "do it".to_string(),
-    //     };
-    //
-    //     let edges = vec![&edge1];
-    //
-    //     fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
-    //         Some(())
-    //     }
-    //
-    //     let mut state_machine = StateMachine::new(
-    //         &state1,
-    //         (),
-    //         states,
-    //         edges,
-    //         &(event_handler as EventHandler<(), String, ()>),
-    //         None,
-    //         None,
-    //         None,
-    //         None,
-    //         None,
-    //     );
-    //
-    //     let event1 = Event {
-    //         id: "first event".to_string(),
-    //         payload: (),
-    //     };
-    //
-    //     state_machine.dispatch(&event1);
-    //
-    //     assert_eq!(state_machine.current_state, &state2);
-    // }
-    //
-    // #[test]
-    // fn it_appropriately_does_not_transition() {
-    //     let state1 = State {
-    //         id: "first_state".to_string(),
-    //     };
-    //     let state2 = State {
-    //         id: "second_state".to_string(),
-    //     };
-    //     let states = vec![&state1, &state2];
-    //
-    //     let edge1 = Edge {
-    //         id: "from first to second".to_string(),
-    //         from_state: &state1,
-    //         to_state: &state2,
-    //         info: "do it".to_string(),
-    //     };
-    //
-    //     let edges = vec![&edge1];
-    //
-    //     fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
-    //         None
-    //     }
-    //
-    //     let mut state_machine = StateMachine::new(
-    //         &state1,
-    //         (),
-    //         states,
-    //         edges,
-    //         &(event_handler as EventHandler<(), String, ()>),
-    //         None,
-    //         None,
-    //         None,
-    //         None,
-    //         None,
-    //     );
-    //
-    //     let event1 = Event {
-    //         id: "first event".to_string(),
-    //         payload: (),
-    //     };
-    //
-    //     state_machine.dispatch(&event1);
-    //
-    //     assert_eq!(state_machine.current_state, &state1);
-    // }
-    //
-    // #[test]
-    // #[should_panic(expected = "Cannot have multiple transitioning edges")]
-    // fn it_panics_on_multiple_transitions() {
-    //     let state1 = State {
-    //         id: "first_state".to_string(),
-    //     };
-    //     let state2 = State {
-    //         id: "second_state".to_string(),
-    //     };
-    //     let states = vec![&state1, &state2];
-    //
-    //     let edge1 = Edge {
-    //         id: "from first to second".to_string(),
-    //         from_state: &state1,
-    //         to_state: &state2,
-    //         info: "do it".to_string(),
-    //     };
-    //     let edge2 = Edge {
-    //         id: "from first to second".to_string(),
-    //         from_state: &state1,
-    //         to_state: &state2,
-    //         info: "do it again".to_string(),
-    //     };
-    //
-    //     let edges = vec![&edge1, &edge2];
-    //
-    //     fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
-    //         Some(())
-    //     }
-    //
-    //     let mut state_machine = StateMachine::new(
-    //         &state1,
-    //         (),
-    //         states,
-    //         edges,
-    //         &(event_handler as EventHandler<(), String, ()>),
-    //         None,
-    //         None,
-    //         None,
-    //         None,
-    //         None,
-    //     );
-    //
-    //     let event1 = Event {
-    //         id: "first event".to_string(),
-    //         payload: (),
-    //     };
-    //
-    //     state_machine.dispatch(&event1);
-    // }
+        let definition = |states: Vec<State>| MachineDefinition::<String> {
+            initial_state_id: "a".to_string(),
+            states,
+            edges: Vec::new(),
+        };
+        assert_eq!(
+            definition(vec![State::new("a").with_parent("b"), State::new("b").with_parent("a")])
+                .validate(),
+            Err(DefinitionError::ParentCycle { id: "a".to_string() })
+        );
+        assert_eq!(
+            definition(vec![State::new("a").with_initial("b"), State::new("b")]).validate(),
+            Err(DefinitionError::InvalidInitialChild {
+                id: "a".to_string(),
+                child_id: "b".to_string(),
+            })
+        );
+    }
 
     #[test]
-    fn it_calls_hooks<'a, 'c>() {
-        let state1 = State {
-            id: "first_state".to_string(),
+    fn it_dispatches_events_into_every_parallel_region() {
+        let mut state_machine = StateMachineBuilder::new()
+            .state("connected")
+            .state("auth")
+            .state("anonymous")
+            .state("signed_in")
+            .state("transport")
+            .state("polling")
+            .state("streaming")
+            .state("disconnected")
+            .parallel("connected")
+            .parent("auth", "connected")
+            .parent("anonymous", "auth")
+            .parent("signed_in", "auth")
+            .initial_child("auth", "anonymous")
+            .parent("transport", "connected")
+            .parent("polling", "transport")
+            .parent("streaming", "transport")
+            .initial_child("transport", "polling")
+            .edge("sign in", "anonymous", "signed_in", "sign in".to_string())
+            .edge("upgrade", "polling", "streaming", "upgrade".to_string())
+            .edge("sign out", "signed_in", "anonymous", "upgrade".to_string())
+            .edge("drop", "connected", "disconnected", "drop".to_string())
+            .edge("reconnect", "disconnected", "streaming", "reconnect".to_string())
+            .edge("fall back", "streaming", "polling", "fall back".to_string())
+            .edge("give up", "connected", "disconnected", "fall back".to_string())
+            .initial("connected")
+            .context(())
+            .handler(accept_matching_info)
+            .build()
+            .unwrap();
+        let ids = |state_machine: &StateMachine<(), String, ()>| {
+            state_machine
+                .active_states()
+                .iter()
+                .map(|state| state_machine.state(*state).unwrap().id().to_string())
+                .collect::<Vec<_>>()
         };
-        let state2 = State {
-            id: "second_state".to_string(),
+        assert_eq!(ids(&state_machine), vec!["anonymous", "polling"]);
+        assert_eq!(state_machine.current_state(), state_machine.state_id("connected"));
+
+        let sign_in = Event::new("sign in", ());
+        let upgrade = Event::new("upgrade", ());
+        let drop = Event::new("drop", ());
+        let reconnect = Event::new("reconnect", ());
+        state_machine.dispatch(sign_in.clone());
+        assert_eq!(ids(&state_machine), vec!["signed_in", "polling"]);
+
+        // Both regions take an edge for the same event.
+        state_machine.dispatch(upgrade.clone());
+        assert_eq!(ids(&state_machine), vec!["anonymous", "streaming"]);
+        assert_eq!(state_machine.transition_history.len(), 3);
+        assert!(state_machine.configuration().contains(&state_machine.state_id("auth").unwrap()));
+
+        // Bubbling from both regions to the same edge only takes it once.
+        state_machine.dispatch(drop.clone());
+        assert_eq!(ids(&state_machine), vec!["disconnected"]);
+        assert_eq!(state_machine.transition_history.len(), 4);
+
+        // Entering one region directly still enters the other at its initial state.
+        state_machine.dispatch(reconnect.clone());
+        assert_eq!(ids(&state_machine), vec!["anonymous", "streaming"]);
+
+        // An edge of a region wins over the edge of an ancestor another region bubbled up to,
+        // as taking both would exit the state the first one enters.
+        let outcome = state_machine.try_dispatch(Event::new("fall back", ())).unwrap();
+        assert_eq!(outcome.edge, state_machine.edge_id("fall back"));
+        assert_eq!(ids(&state_machine), vec!["anonymous", "polling"]);
+        assert_eq!(state_machine.transition_history.len(), 6);
+    }
+
+    #[test]
+    fn it_resumes_from_history_states() {
+        let mut state_machine = StateMachineBuilder::new()
+            .state("on")
+            .state("playing")
+            .state("first_track")
+            .state("second_track")
+            .state("settings")
+            .state("deep_history")
+            .state("shallow_history")
+            .state("paused")
+            .parent("playing", "on")
+            .parent("first_track", "playing")
+            .parent("second_track", "playing")
+            .parent("settings", "on")
+            .parent("deep_history", "on")
+            .parent("shallow_history", "on")
+            .initial_child("on", "settings")
+            .initial_child("playing", "first_track")
+            .history("deep_history", HistoryKind::Deep)
+            .history("shallow_history", HistoryKind::Shallow)
+            .edge("play", "settings", "playing", "play".to_string())
+            .edge("next", "first_track", "second_track", "next".to_string())
+            .edge("pause", "on", "paused", "pause".to_string())
+            .edge("resume", "paused", "deep_history", "resume".to_string())
+            .edge("restart", "paused", "shallow_history", "restart".to_string())
+            .initial("paused")
+            .context(())
+            .handler(accept_matching_info)
+            .build()
+            .unwrap();
+        let play = Event::new("play", ());
+        let next = Event::new("next", ());
+        let pause = Event::new("pause", ());
+        let resume = Event::new("resume", ());
+        let restart = Event::new("restart", ());
+
+        // Without history to restore, the parent is entered at its initial state.
+        state_machine.dispatch(resume.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("settings"));
+
+        state_machine.dispatch(play.clone());
+        state_machine.dispatch(next.clone());
+        state_machine.dispatch(pause.clone());
+        state_machine.dispatch(resume.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("second_track"));
+
+        state_machine.dispatch(pause.clone());
+        state_machine.dispatch(restart.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("first_track"));
+
+        // The states to resume are kept by the machine, whatever the history store keeps.
+        state_machine.set_history_store(NoHistory);
+        state_machine.dispatch(next.clone());
+        state_machine.dispatch(pause.clone());
+        let mut restored = StateMachine::restore(
+            state_machine.definition(),
+            state_machine.snapshot(),
+            accept_matching_info,
+        )
+        .unwrap();
+        for state_machine in [&mut state_machine, &mut restored].iter_mut() {
+            state_machine.dispatch(resume.clone());
+            assert_eq!(state_machine.current_state(), state_machine.state_id("second_track"));
+        }
+    }
+
+    #[test]
+    fn it_finishes_in_a_final_state() {
+        let mut completed = Vec::new();
+        let mut state_machine = StateMachineBuilder::new()
+            .state("running")
+            .state("step")
+            .state("step_done")
+            .state("done")
+            .parent("step", "running")
+            .parent("step_done", "running")
+            .initial_child("running", "step")
+            .final_state("step_done")
+            .final_state("done")
+            .edge("finish step", "step", "step_done", "finish step".to_string())
+            .edge("finish", "running", "done", "finish".to_string())
+            .initial("running")
+            .context(())
+            .handler(accept_matching_info)
+            .on_completion(
+                |_: &Event<()>, state: &State, _: &(), _: &Vec<State>, _: &Vec<Edge<String>>| {
+                    completed.push(state.id().to_string())
+                },
+            )
+            .build()
+            .unwrap();
+        let finish_step = Event::new("finish step", ());
+        let finish = Event::new("finish", ());
+
+        // A final state nested in another state does not finish the machine.
+        state_machine.dispatch(finish_step.clone());
+        assert!(!state_machine.is_finished());
+
+        state_machine.dispatch(finish.clone());
+        assert!(state_machine.is_finished());
+        assert_eq!(state_machine.final_state(), state_machine.state_id("done"));
+        assert_eq!(
+            state_machine.try_dispatch(finish.clone()),
+            Err(DispatchError::Finished {
+                state_id: "done".to_string()
+            })
+        );
+        assert_eq!(state_machine.transition_history.len(), 2);
+        std::mem::drop(state_machine);
+        assert_eq!(completed, vec!["done"]);
+    }
+
+    #[test]
+    fn it_takes_eventless_edges_until_the_configuration_is_stable() {
+        let ended = AtomicUsize::new(0);
+        let builder = || {
+            StateMachineBuilder::new()
+                .state("idle")
+                .state("check")
+                .state("small")
+                .state("large")
+                .edge("add", "idle", "check", ())
+                .edge("is small", "check", "small", ())
+                .edge("is large", "check", "large", ())
+                .edge("reset", "small", "idle", ())
+                .eventless("is small")
+                .eventless("is large")
+                .eventless("reset")
+                .action("add", |_: &Event<u32>, total: &u32| total + 1)
+                .guard("is small", |_: &Event<u32>, total: &u32| *total < 3)
+                .guard("is large", |_: &Event<u32>, total: &u32| *total >= 3)
+                .guard("reset", |_: &Event<u32>, total: &u32| *total < 2)
+                .initial("idle")
+                .context(0)
         };
-        let states = vec![&state1, &state2];
+        let add = Event::new("add", 0);
+
+        let mut state_machine: StateMachine<u32, (), u32> = builder().build().unwrap();
+        state_machine.dispatch(add.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("idle"));
+        assert_eq!(state_machine.transition_history.len(), 3);
+        state_machine.dispatch(add.clone());
+        assert_eq!(state_machine.current_state(), state_machine.state_id("small"));
+        assert_eq!(state_machine.transition_history.len(), 5);
+
+        // Undo and redo take back and take again every hop of a dispatch.
+        state_machine.undo().unwrap();
+        assert_eq!(state_machine.current_state(), state_machine.state_id("idle"));
+        assert_eq!(state_machine.current_context, 1);
+        assert_eq!(state_machine.transition_history.len(), 3);
+        state_machine.redo().unwrap();
+        assert_eq!(state_machine.current_state(), state_machine.state_id("small"));
+        assert_eq!(state_machine.current_context, 2);
+        assert_eq!(state_machine.transition_history.len(), 5);
+        assert!(!state_machine.can_redo());
+
+        // Eventless edges are never taken for an event on their own.
+        let outcome = state_machine.try_dispatch(add.clone()).unwrap();
+        assert!(!outcome.transitioned());
+
+        let mut state_machine: StateMachine<u32, (), u32> = builder()
+            .edge("loop", "idle", "idle", ())
+            .eventless("loop")
+            .guard("loop", |_: &Event<u32>, _: &u32| true)
+            .eventless_step_limit(5)
+            .on_end_dispatch(
+                |_: &Event<u32>, _: &State, _: &u32, _: &Vec<State>, _: &Vec<Edge<()>>| {
+                    ended.fetch_add(1, Ordering::SeqCst);
+                },
+            )
+            .build()
+            .unwrap();
+        let result = state_machine.try_dispatch(add.clone());
+        assert_eq!(
+            result,
+            Err(DispatchError::PartlyApplied {
+                outcome: DispatchOutcome {
+                    previous_state: state_machine.state_id("idle").unwrap(),
+                    new_state: state_machine.state_id("idle").unwrap(),
+                    edge: state_machine.edge_id("add"),
+                    resolved_by: None,
+                },
+                error: Box::new(DispatchError::EventlessLoop {
+                    state_id: "idle".to_string(),
+                    limit: 5,
+                }),
+            })
+        );
+        // The edges taken before the loop was detected are kept, and the dispatch ended.
+        assert_eq!(state_machine.transition_history.len(), 6);
+        std::mem::drop(state_machine);
+        assert_eq!(ended.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn it_dispatches_raised_events_after_the_current_step() {
+        let ship = Event::new("ship", ());
+        let notify = Event::new("notify", ());
+        let pay = Event::new("pay", ());
+        let event_queue = EventQueue::new();
+        let raise = event_queue.clone();
+        let dispatches = AtomicUsize::new(0);
+        let mut state_machine = StateMachineBuilder::new()
+            .state("created")
+            .state("paid")
+            .state("shipped")
+            .state("notified")
+            .event_queue(event_queue)
+            .edge("pay", "created", "paid", "pay".to_string())
+            .edge("ship", "paid", "shipped", "ship".to_string())
+            .edge("notify", "shipped", "notified", "notify".to_string())
+            .initial("created")
+            .context(())
+            .handler(accept_matching_info)
+            .on_enter(
+                "paid",
+                move |_: &Event<()>,
+                      _: &State,
+                      _: &Edge<String>,
+                      _: &(),
+                      _: &Vec<State>,
+                      _: &Vec<Edge<String>>| {
+                    raise.raise(ship.clone());
+                    raise.raise(notify.clone());
+                },
+            )
+            .on_end_dispatch(
+                |event: &Event<()>, state: &State, _: &(), _: &Vec<State>, _: &Vec<Edge<String>>| {
+                    assert_eq!(event.id(), "pay");
+                    assert_eq!(state.id(), "notified");
+                    dispatches.fetch_add(1, Ordering::SeqCst);
+                },
+            )
+            .build()
+            .unwrap();
+        let mut clone = state_machine.clone();
+        state_machine.event_queue().raise(pay.clone());
+        assert_eq!(clone.event_queue().len(), 0);
+        state_machine.event_queue().clear();
+
+        // The hook raises on the queue it captured, but for the clone dispatching.
+        clone.dispatch(pay.clone());
+        assert_eq!(clone.current_state(), clone.state_id("notified"));
+        assert_eq!(state_machine.current_state(), state_machine.state_id("created"));
+        assert!(state_machine.event_queue().is_empty());
+
+        let outcome = state_machine.try_dispatch(pay.clone()).unwrap();
+        assert_eq!(outcome.edge, state_machine.edge_id("pay"));
+        assert_eq!(outcome.new_state, state_machine.state_id("notified").unwrap());
+        assert_eq!(state_machine.transition_history.len(), 3);
+        assert!(state_machine.event_queue().is_empty());
+        std::mem::drop(state_machine);
+        std::mem::drop(clone);
+        assert_eq!(dispatches.load(Ordering::SeqCst), 2);
+    }
 
-        let edge1 = Edge {
-            id: "from first to second".to_string(),
-            from_state: &state1,
-            to_state: &state2,
-            info: "do it".to_string(),
+    #[test]
+    fn it_keeps_history_in_the_configured_store() {
+        let builder = || {
+            StateMachineBuilder::new()
+                .state("off")
+                .state("on")
+                .edge("switch on", "off", "on", ())
+                .edge("switch off", "on", "off", ())
+                .action("switch on", |_: &Event<()>, count: &u32| count + 1)
+                .action("switch off", |_: &Event<()>, count: &u32| count + 1)
+                .initial("off")
+                .context(0)
         };
+        fn run(mut state_machine: StateMachine<(), (), u32>) -> StateMachine<(), (), u32> {
+            for _ in 0..5 {
+                state_machine.dispatch(Event::new("switch", ()));
+            }
+            state_machine
+        }
+
+        let state_machine = run(builder().build().unwrap());
+        assert_eq!(state_machine.transition_history.len(), 5);
+
+        let state_machine =
+            run(builder().history_store(RingBufferHistory::new(2)).build().unwrap());
+        let contexts = state_machine
+            .transition_history
+            .iter()
+            .map(|record| record.context().copied())
+            .collect::<Vec<_>>();
+        assert_eq!(contexts, vec![Some(3), Some(4)]);
+        assert_eq!(state_machine.clone().transition_history.len(), 2);
+
+        let state_machine = run(builder().history_store(IdsOnlyHistory::new()).build().unwrap());
+        assert_eq!(state_machine.transition_history.len(), 5);
+        assert_eq!(state_machine.transition_history[4].context(), None);
+        assert_eq!(
+            state_machine.transition_history[4].edge(),
+            state_machine.edge_id("switch on").unwrap()
+        );
+        let json = serde_json::to_string(&state_machine.snapshot()).unwrap();
+        assert!(!json.contains("\"context\""));
+
+        let mut state_machine = run(builder().build().unwrap());
+        state_machine.set_history_store(NoHistory);
+        state_machine.dispatch(Event::new("switch", ()));
+        assert!(state_machine.transition_history.is_empty());
+        assert_eq!(state_machine.current_context, 6);
+    }
+
+    #[test]
+    fn it_rewinds_undoes_and_redoes_transitions() {
+        let mut state_machine: StateMachine<(), (), Vec<String>> = StateMachineBuilder::new()
+            .state("editing")
+            .state("draft")
+            .state("review")
+            .state("published")
+            .parent("draft", "editing")
+            .parent("review", "editing")
+            .initial_child("editing", "draft")
+            .edge("submit", "draft", "review", ())
+            .edge("publish", "editing", "published", ())
+            .edge("approve", "review", "published", ())
+            .edge("reject", "review", "draft", ())
+            .trigger("submit", "submit")
+            .trigger("publish", "publish")
+            .trigger("approve", "decide")
+            .trigger("reject", "decide")
+            .action("submit", |event: &Event<()>, log: &Vec<String>| {
+                [log.clone(), vec![event.id().to_string()]].concat()
+            })
+            .action("publish", |event: &Event<()>, log: &Vec<String>| {
+                [log.clone(), vec![event.id().to_string()]].concat()
+            })
+            .action("approve", |_: &Event<()>, log: &Vec<String>| log.clone())
+            .action("reject", |_: &Event<()>, log: &Vec<String>| log.clone())
+            .initial("editing")
+            .context(Vec::new())
+            .build()
+            .unwrap();
+        state_machine.dispatch(Event::new("submit", ()));
+        state_machine.dispatch(Event::new("publish", ()));
+        assert_eq!(state_machine.current_state(), state_machine.state_id("published"));
+
+        state_machine.undo().unwrap();
+        assert_eq!(state_machine.current_state(), state_machine.state_id("review"));
+        assert_eq!(state_machine.current_context, vec!["submit"]);
+        state_machine.rewind_to(0).unwrap();
+        assert_eq!(state_machine.current_state(), state_machine.state_id("draft"));
+        assert!(state_machine.current_context.is_empty());
+        assert!(state_machine.transition_history.is_empty());
+        assert_eq!(state_machine.undo(), Err(RewindError::NothingToUndo));
+
+        state_machine.redo().unwrap();
+        state_machine.redo().unwrap();
+        assert_eq!(state_machine.current_state(), state_machine.state_id("published"));
+        assert_eq!(state_machine.current_context, vec!["submit", "publish"]);
+        assert_eq!(state_machine.transition_history.len(), 2);
+        assert_eq!(state_machine.redo(), Err(RewindError::NothingToRedo));
 
-        let edges = vec![&edge1];
+        // A new dispatch discards the transitions left to redo.
+        state_machine.undo().unwrap();
+        assert!(state_machine.can_redo());
+        assert!(state_machine.try_dispatch(Event::new("decide", ())).is_err());
+        assert!(state_machine.can_redo());
+        state_machine.dispatch(Event::new("submit", ()));
+        assert!(!state_machine.can_redo());
+        assert_eq!(
+            state_machine.rewind_to(2),
+            Err(RewindError::OutOfRange { index: 2, len: 1 })
+        );
+    }
+
+    #[test]
+    fn it_calls_hooks() {
+        let state1 = State::new("first_state");
+        let state2 = State::new("second_state");
+        let states = vec![state1, state2];
+
+        let edge1 = Edge::new(
+            "from first to second".to_string(),
+            StateId(0),
+            StateId(1),
+            "do it".to_string(),
+        );
+
+        let edges = vec![edge1];
 
         fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
             Some(())
@@ -604,11 +3674,11 @@ mod tests {
         let mut start_dispatch_hook_called = false;
 
         let start_dispatch_hook= |
-            event: &Event<()>,
-            current_state: &State,
-            current_context: &(),
-            states: &Vec<&State>,
-            edges: &Vec<&Edge<String>>
+            _event: &Event<()>,
+            _current_state: &State,
+            _current_context: &(),
+            _states: &Vec<State>,
+            _edges: &Vec<Edge<String>>
         | {
             println!("start_dispatch_hook called");
             start_dispatch_hook_called = true;
@@ -616,10 +3686,10 @@ mod tests {
 
         let end_dispatch_hook= |
             event: &Event<()>,
-            current_state: &State,
-            current_context: &(),
-            states: &Vec<&State>,
-            edges: &Vec<&Edge<String>>
+            _current_state: &State,
+            _current_context: &(),
+            _states: &Vec<State>,
+            edges: &Vec<Edge<String>>
         | {
             for edge in edges {
                 println!("{:?}", edge);
@@ -629,11 +3699,25 @@ mod tests {
 
         let enter_state_hook = |
             event: &Event<()>,
-            edge: &Edge<String>,
-            current_state: &State,
-            current_context: &(),
-            states: &Vec<&State>,
-            edges: &Vec<&Edge<String>>
+            _current_state: &State,
+            _edge: &Edge<String>,
+            _current_context: &(),
+            _states: &Vec<State>,
+            edges: &Vec<Edge<String>>
+        | {
+            for edge in edges {
+                println!("{:?}", edge);
+            }
+            println!("{:?}", event);
+        };
+
+        let exit_state_hook = |
+            event: &Event<()>,
+            _current_state: &State,
+            _edge: &Edge<String>,
+            _current_context: &(),
+            _states: &Vec<State>,
+            edges: &Vec<Edge<String>>
         | {
             for edge in edges {
                 println!("{:?}", edge);
@@ -641,26 +3725,12 @@ mod tests {
             println!("{:?}", event);
         };
 
-        // let exit_state_hook = |
-        //     event: &Event<()>,
-        //     edge: &Edge<String>,
-        //     current_state: &State,
-        //     current_context: &(),
-        //     states: &Vec<&State>,
-        //     edges: &Vec<&Edge<String>>
-        // | {
-        //     for edge in edges {
-        //         println!("{:?}", edge);
-        //     }
-        //     println!("{:?}", event);
-        // };
-        //
         let traverse_edge_hook = |
             event: &Event<()>,
-            edge: &Edge<String>,
-            current_context: &(),
-            states: &Vec<&State>,
-            edges: &Vec<&Edge<String>>
+            _edge: &Edge<String>,
+            _current_context: &(),
+            _states: &Vec<State>,
+            edges: &Vec<Edge<String>>
         | {
             for edge in edges {
                 println!("{:?}", edge);
@@ -669,16 +3739,16 @@ mod tests {
         };
 
         let mut state_machine: StateMachine<(), String, ()> = StateMachine::new(
-            &state1,
+            StateId(0),
             (),
             states,
             edges,
-            &(event_handler as EventHandler<(), String, ()>),
+            event_handler as EventHandler<(), String, ()>,
             Some(start_dispatch_hook),
             Some(end_dispatch_hook),
+            Some(enter_state_hook),
+            Some(exit_state_hook),
             Some(traverse_edge_hook),
-            // None,
-            // None,
         );
 
         let event1 = Event {
@@ -686,11 +3756,11 @@ mod tests {
             payload: (),
         };
 
-        state_machine.dispatch(&event1);
+        state_machine.dispatch(event1.clone());
         std::mem::drop(state_machine);
         println!("start_dispatch_hook_called: {}", &start_dispatch_hook_called);
-        assert_eq!(start_dispatch_hook_called, true);
-        // assert_eq!(&state_machine.current_state.unwrap(), &state2);
+        assert!(start_dispatch_hook_called);
+        // assert_eq!(&state_machine.current_state().unwrap(), &state2);
 
     }
 }
@@ -0,0 +1,105 @@
+use crate::{lock, Event};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+
+type Events<EventPayload> = Arc<Mutex<VecDeque<Arc<Event<EventPayload>>>>>;
+
+/// A handle to the events raised while a machine is dispatching, shared between every clone
+/// of the handle.
+///
+/// Hooks, handlers, guards and actions capture a clone of the queue to `raise` follow-up
+/// events, which the machine dispatches in order once the current step completes, see
+/// `StateMachine::try_dispatch`.
+pub struct EventQueue<EventPayload> {
+    events: Events<EventPayload>,
+    /// The events of the machine dispatching on each thread, shared with the queues of the
+    /// machine's clones, so events raised on any of them go to the machine dispatching.
+    dispatching: Arc<Mutex<HashMap<ThreadId, Events<EventPayload>>>>,
+}
+
+impl<EventPayload> EventQueue<EventPayload> {
+    pub fn new() -> EventQueue<EventPayload> {
+        EventQueue {
+            events: Arc::new(Mutex::new(VecDeque::new())),
+            dispatching: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Queues `event` behind every event raised before it, on the queue of the machine
+    /// dispatching on this thread if it shares hooks with this one, and otherwise on this
+    /// queue.
+    pub fn raise(&self, event: impl Into<Arc<Event<EventPayload>>>) {
+        let dispatching = lock(&self.dispatching).get(&thread::current().id()).cloned();
+        lock(dispatching.as_ref().unwrap_or(&self.events)).push_back(event.into());
+    }
+
+    pub fn len(&self) -> usize {
+        lock(&self.events).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        lock(&self.events).is_empty()
+    }
+
+    /// Drops every queued event.
+    pub fn clear(&self) {
+        lock(&self.events).clear();
+    }
+
+    /// A queue of its own holding the events queued on this one, for a clone of the machine.
+    pub(crate) fn copy(&self) -> EventQueue<EventPayload> {
+        EventQueue {
+            events: Arc::new(Mutex::new(lock(&self.events).clone())),
+            dispatching: self.dispatching.clone(),
+        }
+    }
+
+    pub(crate) fn pop(&self) -> Option<Arc<Event<EventPayload>>> {
+        lock(&self.events).pop_front()
+    }
+
+    /// Sends the events raised on this thread to this queue until the guard is dropped.
+    pub(crate) fn dispatching(&self) -> Dispatching<EventPayload> {
+        let thread = thread::current().id();
+        let previous = lock(&self.dispatching).insert(thread, self.events.clone());
+        Dispatching {
+            dispatching: self.dispatching.clone(),
+            thread,
+            previous,
+        }
+    }
+}
+
+impl<EventPayload> Clone for EventQueue<EventPayload> {
+    fn clone(&self) -> Self {
+        EventQueue {
+            events: self.events.clone(),
+            dispatching: self.dispatching.clone(),
+        }
+    }
+}
+
+impl<EventPayload> Default for EventQueue<EventPayload> {
+    fn default() -> Self {
+        EventQueue::new()
+    }
+}
+
+/// Routes raised events to a queue while a machine dispatches, see
+/// `EventQueue::dispatching`. Dropping it routes them back to where they went before.
+pub(crate) struct Dispatching<EventPayload> {
+    dispatching: Arc<Mutex<HashMap<ThreadId, Events<EventPayload>>>>,
+    thread: ThreadId,
+    previous: Option<Events<EventPayload>>,
+}
+
+impl<EventPayload> Drop for Dispatching<EventPayload> {
+    fn drop(&mut self) {
+        let mut dispatching = lock(&self.dispatching);
+        match self.previous.take() {
+            Some(previous) => dispatching.insert(self.thread, previous),
+            None => dispatching.remove(&self.thread),
+        };
+    }
+}
@@ -0,0 +1,323 @@
+use crate::{
+    DeserializableTransitionRecord, DispatchError, Event, HydrationError, StateMachine,
+    UnboundedHistory,
+};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::Arc;
+
+/// The first point where a replay stopped following the recorded history. Every `index` is
+/// the index of a record in the recorded history.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Divergence<Context> {
+    /// Transition `index` was recorded through edge `expected_edge_id`, but the replay took
+    /// edge `actual_edge_id`, or none.
+    Edge {
+        index: usize,
+        expected_edge_id: String,
+        actual_edge_id: Option<String>,
+    },
+    /// The replay took transition `index`, past the end of the recorded history.
+    UnexpectedTransition { index: usize, edge_id: String },
+    /// Transition `index` left the machine with a different context than recorded.
+    Context {
+        index: usize,
+        expected: Context,
+        actual: Context,
+    },
+    /// Dispatching the event for transition `index` failed.
+    Dispatch { index: usize, error: DispatchError },
+    /// The recorded history references an event that was not provided.
+    Hydration(HydrationError),
+}
+
+impl<Context: Debug> Display for Divergence<Context> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Divergence::Edge {
+                index,
+                expected_edge_id,
+                actual_edge_id: Some(actual_edge_id),
+            } => write!(
+                f,
+                "Transition {} took edge {} instead of {}",
+                index, actual_edge_id, expected_edge_id
+            ),
+            Divergence::Edge {
+                index,
+                expected_edge_id,
+                actual_edge_id: None,
+            } => write!(
+                f,
+                "Transition {} through edge {} was not taken",
+                index, expected_edge_id
+            ),
+            Divergence::UnexpectedTransition { index, edge_id } => {
+                write!(f, "Transition {} through edge {} was not recorded", index, edge_id)
+            }
+            Divergence::Context {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Transition {} resulted in context {:?} instead of {:?}",
+                index, actual, expected
+            ),
+            Divergence::Dispatch { index, error } => {
+                write!(f, "Dispatch for transition {} failed: {}", index, error)
+            }
+            Divergence::Hydration(error) => Display::fmt(error, f),
+        }
+    }
+}
+
+impl<Context: Debug> Error for Divergence<Context> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Divergence::Dispatch { error, .. } => Some(error),
+            Divergence::Hydration(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, EventPayload, EdgeInfo, Context> StateMachine<'a, EventPayload, EdgeInfo, Context>
+where
+    EventPayload: Debug + Send + Sync + 'a,
+    EdgeInfo: Debug,
+    Context: Debug + Clone + PartialEq + Send + 'a,
+{
+    /// Dispatches `events` in order, checking every transition they cause against
+    /// `recorded`, which is typically the history in the snapshot of another machine that
+    /// was given the same events.
+    ///
+    /// The machine should be fresh, or at least in the state and context `recorded` starts
+    /// from. Transitions are compared by edge id and by the context they result in, where
+    /// both are known; the last one recorded should result in `expected_context`, unless
+    /// that is `None`. Replaying stops at the first divergence; the transitions taken until
+    /// then stay in `transition_history`.
+    pub fn replay(
+        &mut self,
+        events: impl IntoIterator<Item = impl Into<Arc<Event<EventPayload>>>>,
+        recorded: &[DeserializableTransitionRecord<EventPayload, Context>],
+        expected_context: Option<&Context>,
+    ) -> Result<(), Divergence<Context>> {
+        self.with_replay_history(|state_machine| {
+            for event in events {
+                state_machine.replay_event(event.into(), recorded, expected_context, false)?;
+            }
+            state_machine.check_replay_finished(recorded)
+        })
+    }
+
+    /// Like `replay`, but dispatches the events kept in `recorded`, failing with
+    /// `Divergence::Hydration` at a record that kept only the id of its event. A
+    /// transition recorded for an event raised or taken without one during an earlier
+    /// dispatch is expected to be taken again by that dispatch.
+    pub fn replay_history(
+        &mut self,
+        recorded: &[DeserializableTransitionRecord<EventPayload, Context>],
+        expected_context: Option<&Context>,
+    ) -> Result<(), Divergence<Context>> {
+        self.with_replay_history(|state_machine| {
+            while let Some(record) = recorded.get(state_machine.transition_history.len()) {
+                let event = record.find_event(&[]).map_err(Divergence::Hydration)?;
+                state_machine.replay_event(event, recorded, expected_context, true)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs `replay` with an empty history that keeps every record, so the replayed
+    /// transitions line up with the recorded ones, then moves what it recorded to the
+    /// machine's own history store.
+    fn with_replay_history(
+        &mut self,
+        replay: impl FnOnce(&mut Self) -> Result<(), Divergence<Context>>,
+    ) -> Result<(), Divergence<Context>> {
+        let history_store =
+            std::mem::replace(&mut self.transition_history, Box::new(UnboundedHistory::new()));
+        let result = replay(self);
+        let mut replayed = Vec::new();
+        while let Some(record) = self.transition_history.pop() {
+            replayed.push(record);
+        }
+        self.transition_history = history_store;
+        for record in replayed.into_iter().rev() {
+            self.transition_history.push(record);
+        }
+        result
+    }
+
+    /// Dispatches `event` and compares the transitions it causes with `recorded`. With
+    /// `expect_transition`, causing none is a divergence.
+    fn replay_event(
+        &mut self,
+        event: Arc<Event<EventPayload>>,
+        recorded: &[DeserializableTransitionRecord<EventPayload, Context>],
+        expected_context: Option<&Context>,
+        expect_transition: bool,
+    ) -> Result<(), Divergence<Context>> {
+        let start = self.transition_history.len();
+        self.try_dispatch(event)
+            .map_err(|error| Divergence::Dispatch {
+                index: start,
+                error,
+            })?;
+        let end = self.transition_history.len();
+        if expect_transition && start == end {
+            return self.check_replay_finished(recorded);
+        }
+        for index in start..end {
+            let edge_id = &self.edges[self.transition_history[index].edge.0].id;
+            let expected = recorded
+                .get(index)
+                .ok_or_else(|| Divergence::UnexpectedTransition {
+                    index,
+                    edge_id: edge_id.clone(),
+                })?;
+            if expected.edge_id != *edge_id {
+                return Err(Divergence::Edge {
+                    index,
+                    expected_edge_id: expected.edge_id.clone(),
+                    actual_edge_id: Some(edge_id.clone()),
+                });
+            }
+            // A record holds the context its transition was taken from, which is the one
+            // the transition before it resulted in. The last one results in the current one.
+            let actual = match index + 1 {
+                next if next < end => self.transition_history[next].context.as_ref(),
+                _ => Some(&self.current_context),
+            };
+            let expected = match recorded.get(index + 1) {
+                Some(record) => record.context.as_ref(),
+                None => expected_context,
+            };
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                if expected != actual {
+                    return Err(Divergence::Context {
+                        index,
+                        expected: expected.clone(),
+                        actual: actual.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails with the first recorded transition the replay has not taken.
+    fn check_replay_finished(
+        &self,
+        recorded: &[DeserializableTransitionRecord<EventPayload, Context>],
+    ) -> Result<(), Divergence<Context>> {
+        let index = self.transition_history.len();
+        match recorded.get(index) {
+            Some(record) => Err(Divergence::Edge {
+                index,
+                expected_edge_id: record.edge_id.clone(),
+                actual_edge_id: None,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::sync::Arc;
+
+    fn build(step: u32) -> StateMachine<'static, (), String, u32> {
+        build_with_shipping(step, step)
+    }
+
+    fn build_with_shipping(step: u32, shipping: u32) -> StateMachine<'static, (), String, u32> {
+        StateMachineBuilder::new()
+            .state("created")
+            .state("paid")
+            .state("shipped")
+            .edge("pay", "created", "paid", "pay".to_string())
+            .edge("ship", "paid", "shipped", "ship".to_string())
+            .edge("refund", "paid", "created", "refund".to_string())
+            .initial("created")
+            .context(0)
+            .handler(move |event: &Event<()>, edge: &Edge<String>, total: &u32| {
+                if edge.info() != event.id() {
+                    None
+                } else if edge.id() == "ship" {
+                    Some(total + shipping)
+                } else {
+                    Some(total + step)
+                }
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn it_replays_events_against_a_recorded_history() {
+        let events = ["pay", "refund", "pay", "ship"]
+            .iter()
+            .map(|id| Arc::new(Event::new(*id, ())))
+            .collect::<Vec<_>>();
+        let mut production = build(1);
+        for event in &events {
+            production.dispatch(event.clone());
+        }
+        let snapshot = production.snapshot();
+        let recorded = &snapshot.transition_history;
+        let context = Some(&snapshot.current_context);
+
+        assert_eq!(build(1).replay(events.clone(), recorded, context), Ok(()));
+        assert_eq!(build(1).replay_history(recorded, context), Ok(()));
+
+        assert_eq!(
+            build(2).replay(events.clone(), recorded, context),
+            Err(Divergence::Context {
+                index: 0,
+                expected: 1,
+                actual: 2,
+            })
+        );
+        assert_eq!(
+            build_with_shipping(1, 5).replay(events.clone(), recorded, context),
+            Err(Divergence::Context {
+                index: 3,
+                expected: 4,
+                actual: 8,
+            })
+        );
+        // Without the final context, the last transition is only compared by edge.
+        assert_eq!(
+            build_with_shipping(1, 5).replay(events.clone(), recorded, None),
+            Ok(())
+        );
+
+        let mut replayed = build(1);
+        let diverged = replayed.replay(
+            vec![Event::new("pay", ()), Event::new("ship", ())],
+            recorded,
+            context,
+        );
+        assert_eq!(
+            diverged,
+            Err(Divergence::Edge {
+                index: 1,
+                expected_edge_id: "refund".to_string(),
+                actual_edge_id: Some("ship".to_string()),
+            })
+        );
+        assert_eq!(replayed.transition_history.len(), 2);
+
+        assert_eq!(
+            build(1).replay(events[..2].to_vec(), recorded, context),
+            Err(Divergence::Edge {
+                index: 2,
+                expected_edge_id: "pay".to_string(),
+                actual_edge_id: None,
+            })
+        );
+    }
+}
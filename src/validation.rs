@@ -0,0 +1,212 @@
+use crate::{State, StateId, StateMachine};
+use std::collections::HashSet;
+use std::fmt::{Debug, Display, Formatter};
+
+/// A problem `StateMachine::validate` found with the states and edges of a machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    DuplicateState { id: String },
+    DuplicateEdge { id: String },
+    /// Edge `id` leaves or enters `state`, which is not one of the machine's states.
+    DanglingEdge { id: String, state: StateId },
+    /// The initial state is not one of the machine's states.
+    UnknownInitialState { state: StateId },
+    /// No sequence of edges leads from the initial state to state `id`.
+    UnreachableState { id: String },
+    /// State `id` is not final, but neither it nor any of its ancestors has an edge out.
+    DeadEnd { id: String },
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Diagnostic::DuplicateState { id } => write!(f, "Duplicate state id: {}", id),
+            Diagnostic::DuplicateEdge { id } => write!(f, "Duplicate edge id: {}", id),
+            Diagnostic::DanglingEdge { id, state } => write!(
+                f,
+                "Edge {} references a state with index {}, which does not exist",
+                id, state.0
+            ),
+            Diagnostic::UnknownInitialState { state } => {
+                write!(f, "Could not find the initial state with index: {}", state.0)
+            }
+            Diagnostic::UnreachableState { id } => {
+                write!(f, "State {} cannot be reached from the initial state", id)
+            }
+            Diagnostic::DeadEnd { id } => {
+                write!(f, "State {} is not final but has no edges out", id)
+            }
+        }
+    }
+}
+
+impl<'a, EventPayload, EdgeInfo, Context> StateMachine<'a, EventPayload, EdgeInfo, Context>
+where
+    EventPayload: Debug + Send + Sync + 'a,
+    EdgeInfo: Debug,
+    Context: Debug + Send + 'a,
+{
+    /// Checks the states and edges the machine was built with, returning every problem
+    /// found. A state is unreachable when `reachable_states` from the initial state leaves
+    /// it out, and a dead end when it is one of the `sinks` without being final.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let edge_ids = self.edges.iter().map(|edge| edge.id.as_str());
+        let mut diagnostics = check_ids(&self.states, edge_ids, self.initial_state);
+        for edge in &self.edges {
+            for state in [edge.from_state, edge.to_state] {
+                if state.0 >= self.states.len() {
+                    diagnostics.push(Diagnostic::DanglingEdge {
+                        id: edge.id.clone(),
+                        state,
+                    });
+                }
+            }
+        }
+        if self.initial_state.0 >= self.states.len() {
+            return diagnostics;
+        }
+
+        let reachable = self.reachable_states(self.initial_state);
+        let sinks = self.sinks();
+        for (index, state) in self.states.iter().enumerate() {
+            if state.history.is_some() {
+                continue;
+            }
+            if !reachable.contains(&StateId(index)) {
+                diagnostics.push(Diagnostic::UnreachableState {
+                    id: state.id.clone(),
+                });
+            }
+            if !state.final_state && sinks.contains(&StateId(index)) {
+                diagnostics.push(Diagnostic::DeadEnd {
+                    id: state.id.clone(),
+                });
+            }
+        }
+        diagnostics
+    }
+}
+
+/// The checks `MachineDefinition::validate` shares with `StateMachine::validate`: no two
+/// states and no two edges have the same id, and `initial_state` is one of `states`.
+pub(crate) fn check_ids<'s>(
+    states: &[State],
+    edge_ids: impl IntoIterator<Item = &'s str>,
+    initial_state: StateId,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut state_ids = HashSet::new();
+    for state in states {
+        if !state_ids.insert(state.id.as_str()) {
+            diagnostics.push(Diagnostic::DuplicateState {
+                id: state.id.clone(),
+            });
+        }
+    }
+    if initial_state.0 >= states.len() {
+        diagnostics.push(Diagnostic::UnknownInitialState {
+            state: initial_state,
+        });
+    }
+    let mut seen_edge_ids = HashSet::new();
+    for id in edge_ids {
+        if !seen_edge_ids.insert(id) {
+            diagnostics.push(Diagnostic::DuplicateEdge { id: id.to_string() });
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{media_player, NoDispatchHook, NoEdgeTraversalHook, NoStateHook};
+    use crate::*;
+
+    fn build(
+        initial_state: StateId,
+        states: Vec<State>,
+        edges: Vec<(&str, usize, usize)>,
+    ) -> StateMachine<'static, (), String, ()> {
+        fn event_handler(_event: &Event<()>, _edge: &Edge<String>, _context: &()) -> Option<()> {
+            None
+        }
+
+        let edges = edges
+            .into_iter()
+            .map(|(id, from, to)| {
+                Edge::new(id.to_string(), StateId(from), StateId(to), id.to_string())
+            })
+            .collect();
+        StateMachine::new(
+            initial_state,
+            (),
+            states,
+            edges,
+            event_handler as EventHandler<(), String, ()>,
+            None::<NoDispatchHook>,
+            None::<NoDispatchHook>,
+            None::<NoStateHook>,
+            None::<NoStateHook>,
+            None::<NoEdgeTraversalHook>,
+        )
+    }
+
+    #[test]
+    fn it_validates_states_and_edges() {
+        let state_machine = build(
+            StateId(0),
+            vec![
+                State::new("idle"),
+                State::new("running"),
+                State::new("done").with_final(),
+                State::new("stuck"),
+                State::new("orphan"),
+            ],
+            vec![("start", 0, 1), ("finish", 1, 2), ("jam", 1, 3), ("leave", 4, 0)],
+        );
+        assert_eq!(
+            state_machine.validate(),
+            vec![
+                Diagnostic::DeadEnd {
+                    id: "stuck".to_string()
+                },
+                Diagnostic::UnreachableState {
+                    id: "orphan".to_string()
+                },
+            ]
+        );
+
+        let state_machine = build(
+            StateId(0),
+            vec![State::new("idle"), State::new("idle").with_final()],
+            vec![("start", 0, 1), ("start", 0, 1), ("away", 0, 9)],
+        );
+        assert_eq!(
+            state_machine.validate(),
+            vec![
+                Diagnostic::DuplicateState {
+                    id: "idle".to_string()
+                },
+                Diagnostic::DuplicateEdge {
+                    id: "start".to_string()
+                },
+                Diagnostic::DanglingEdge {
+                    id: "away".to_string(),
+                    state: StateId(9),
+                },
+            ]
+        );
+
+        let state_machine = build(StateId(3), vec![State::new("idle").with_final()], vec![]);
+        assert_eq!(
+            state_machine.validate(),
+            vec![Diagnostic::UnknownInitialState { state: StateId(3) }]
+        );
+    }
+
+    #[test]
+    fn it_follows_nested_states_and_history_when_validating() {
+        let state_machine = media_player();
+        assert_eq!(state_machine.validate(), Vec::new());
+    }
+}